@@ -0,0 +1,102 @@
+//! Top k against full evaluation on a corpus with Zipf distributed terms.
+//!
+//! Run with `cargo bench --features bench`.
+#![feature(test)]
+
+extern crate test;
+extern crate perlin;
+extern crate perlin_core;
+
+use std::env::temp_dir;
+use std::fs;
+
+use test::Bencher;
+
+use perlin_core::index::posting::DocId;
+use perlin::field::{Fields, FieldSupplement};
+use perlin::language::{CanApply, PipelineBucket};
+use perlin::query::{Funnel, ToOperands, WeightingOperator, WeightingOptions};
+
+const DOCS: u32 = 20_000;
+const TERMS: usize = 1_000;
+const TERMS_PER_DOC: usize = 40;
+
+/// Builds a `title` and a `body` field. The term of rank r is drawn with a
+/// probability proportional to 1/r. Titles hold the first five terms of a body.
+fn zipf_fields(name: &str) -> Fields<String> {
+    let path = temp_dir().join("perlin_benches").join(name);
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
+    fs::create_dir_all(&path).unwrap();
+    let mut fields = Fields::new();
+    fields.add_field("title".to_string(), &path, FieldSupplement::None).unwrap();
+    fields.add_field("body".to_string(), &path, FieldSupplement::None).unwrap();
+
+    let mut cdf = Vec::with_capacity(TERMS);
+    let mut sum = 0.;
+    for rank in 1..TERMS + 1 {
+        sum += 1. / rank as f64;
+        cdf.push(sum);
+    }
+    // xorshift64, so every run indexes the same corpus
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut sample = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let target = state as f64 / u64::max_value() as f64 * sum;
+        match cdf.binary_search_by(|p| p.partial_cmp(&target).unwrap()) {
+            Ok(rank) | Err(rank) => rank.min(TERMS - 1),
+        }
+    };
+    for doc_id in 0..DOCS {
+        let mut terms = (0..TERMS_PER_DOC).map(|_| sample()).collect::<Vec<_>>();
+        let mut title = terms[..5].to_vec();
+        title.sort();
+        title.dedup();
+        terms.sort();
+        terms.dedup();
+        for rank in title {
+            fields.get_mut("title").unwrap().put(DocId(doc_id), format!("t{}", rank));
+        }
+        for rank in terms {
+            fields.get_mut("body").unwrap().put(DocId(doc_id), format!("t{}", rank));
+        }
+    }
+    fields.commit();
+    fields
+}
+
+fn run(fields: &Fields<String>, terms: &[&str], limit: Option<usize>) -> usize {
+    let mut funnel = Funnel::create(fields);
+    for term in terms {
+        funnel.apply(term.to_string());
+    }
+    let options = WeightingOptions { limit: limit, ..WeightingOptions::default() };
+    WeightingOperator::create_with_options(funnel.to_operands(), vec![], options).count()
+}
+
+#[bench]
+fn common_terms_top_10(b: &mut Bencher) {
+    let fields = zipf_fields("common_terms_top_10");
+    b.iter(|| run(&fields, &["t0", "t1"], Some(10)));
+}
+
+#[bench]
+fn common_terms_all(b: &mut Bencher) {
+    let fields = zipf_fields("common_terms_all");
+    b.iter(|| run(&fields, &["t0", "t1"], None));
+}
+
+#[bench]
+fn mixed_terms_top_10(b: &mut Bencher) {
+    let fields = zipf_fields("mixed_terms_top_10");
+    b.iter(|| run(&fields, &["t0", "t500"], Some(10)));
+}
+
+#[bench]
+fn mixed_terms_all(b: &mut Bencher) {
+    let fields = zipf_fields("mixed_terms_all");
+    b.iter(|| run(&fields, &["t0", "t500"], None));
+}
@@ -6,73 +6,676 @@ pub fn generate_index_struct(ast: &syn::MacroInput) -> quote::Tokens {
     let index_ident = syn::Ident::from(format!("{}Index", ident).to_string());
 
     let ext_id = external_id_field(ast);
+    // Indexes without external ids pass `None` to the warning sink
+    let ext_id_type = match get_external_id_type(&ast.attrs) {
+        Some(ext_id_type) => quote!(#ext_id_type),
+        None => quote!(()),
+    };
     let create_external_ids = create_external_ids(ast);
     let run_query = run_query(ast);
+    let add_document = add_document(ast);
+    let run_scored_query = run_scored_query(ast);
+    let external_id_lookups = external_id_lookups(ast);
+    let weighting_operator = weighting_operator();
+
+    let piped_fields = piped_fields(ast);
+    let pipeline_fields = piped_fields.iter().map(|&(ref field, ref term_ty)| {
+        let pipe_ident = pipeline_ident(field);
+        quote!(pub #pipe_ident: Option<Pipeline<#term_ty, #ident>>,)
+    });
+    let create_pipelines = piped_fields.iter().map(|&(ref field, _)| {
+        let pipe_ident = pipeline_ident(field);
+        quote!(#pipe_ident: None,)
+    });
+    let pipeline_setters = piped_fields.iter().map(|&(ref field, ref term_ty)| {
+        let pipe_ident = pipeline_ident(field);
+        let setter_ident = syn::Ident::from(format!("set_{}_pipeline", field));
+        quote!(
+            pub fn #setter_ident(&mut self, pipe: Pipeline<#term_ty, #ident>) {
+                self.#pipe_ident = Some(pipe);
+            }
+        )
+    });
+    let field_names = piped_fields.iter().map(|&(ref field, _)| field.to_string());
+    let field_pipes = piped_fields.iter().map(|&(ref field, _)| pipeline_ident(field));
+    let checked_field_names = piped_fields.iter().map(|&(ref field, _)| field.to_string());
+    let checked_field_pipes = piped_fields.iter().map(|&(ref field, _)| pipeline_ident(field));
 
     quote!(
         pub struct #index_ident {
             pub documents: #ident,
             pub query_pipeline: Option<QueryPipeline<#ident>>,
+            pub validator: Option<Validator>,
+            pub warning_sink: Option<WarningSink<#ext_id_type>>,
+            pub max_tokens_per_document: Option<usize>,
+            pub overflows: Vec<Overflow>,
+            pub collapses: Vec<Collapse>,
+            pub metadata: Metadata,
             pub doc_counter: DocId,
+            #(#pipeline_fields)*
             #ext_id
         }
 
 
         impl #index_ident {
-            pub fn create(base_path: PathBuf) -> Self {
-                #index_ident {
+            /// Creates an empty index in `base_path`.
+            /// Metadata left there by an earlier index is discarded, as it
+            /// describes documents this index does not contain.
+            pub fn create(base_path: PathBuf) -> io::Result<Self> {
+                let mut metadata = Metadata::load(&base_path.join("metadata.bin"))?;
+                metadata.discard();
+                Ok(#index_ident {
                     documents: #ident::create(&base_path),
                     query_pipeline: None,
+                    validator: None,
+                    warning_sink: None,
+                    max_tokens_per_document: None,
+                    overflows: Vec::new(),
+                    collapses: Vec::new(),
+                    metadata: metadata,
                     doc_counter: DocId::none(),
+                    #(#create_pipelines)*
                     #create_external_ids
-                }
+                })
             }
 
-            pub fn commit(&mut self) {
+            /// Commits all fields and then persists the metadata.
+            /// If persisting fails, the fields stay committed.
+            pub fn commit(&mut self) -> io::Result<()> {
                 self.documents.commit();
+                self.metadata.persist()
+            }
+
+            /// Records that the indexing pipelines fold case with `mode` (see
+            /// `CaseFolder`). It is persisted with the metadata on commit.
+            /// Queries declaring another mode with `Query::case_folding` fail.
+            ///
+            /// Fails if documents were already indexed under another mode.
+            pub fn set_case_folding(&mut self, mode: CaseFolding)
+                                    -> Result<(), CaseFoldingMismatch> {
+                if let Some(index) = self.case_folding() {
+                    if index != mode && self.doc_counter != DocId::none() {
+                        return Err(CaseFoldingMismatch {
+                            index: index,
+                            other: mode,
+                        });
+                    }
+                }
+                self.metadata
+                    .set(CASE_FOLDING_KEY, mode.name().as_bytes())
+                    .expect("Case folding names fit into the metadata");
+                Ok(())
+            }
+
+            /// The mode recorded by `set_case_folding`
+            pub fn case_folding(&self) -> Option<CaseFolding> {
+                self.metadata
+                    .get(CASE_FOLDING_KEY)
+                    .and_then(|name| ::std::str::from_utf8(name).ok())
+                    .and_then(CaseFolding::from_name)
             }
 
             pub fn set_query_pipeline(&mut self, pipe: QueryPipeline<#ident>) {
                 self.query_pipeline = Some(pipe);
             }
 
+            #(#pipeline_setters)*
+
+            #weighting_operator
+
+            /// Sets the validator that `add_document` consults before indexing
+            pub fn set_validator(&mut self, validator: Validator) {
+                self.validator = Some(validator);
+            }
+
+            /// Sets the sink that receives the warnings of documents accepted
+            /// with warnings
+            pub fn set_warning_sink(&mut self, sink: WarningSink<#ext_id_type>) {
+                self.warning_sink = Some(sink);
+            }
+
+            /// Rejects documents containing a field that is unknown or has no
+            /// pipeline set. Then consults the validator.
+            pub fn validate_document(&self, document: &[(Cow<str>, Cow<str>)])
+                                     -> ValidationOutcome {
+                for &(ref field, _) in document {
+                    let has_pipeline = match &**field {
+                        #(#checked_field_names => self.#checked_field_pipes.is_some(),)*
+                        _ => {
+                            return ValidationOutcome::Reject {
+                                reason: format!("Unknown field {}", field),
+                            }
+                        }
+                    };
+                    if !has_pipeline {
+                        return ValidationOutcome::Reject {
+                            reason: format!("Pipeline for field {} not set", field),
+                        };
+                    }
+                }
+                if let Some(ref validator) = self.validator {
+                    validator(document)
+                } else {
+                    ValidationOutcome::Accept
+                }
+            }
+
+            fn report_warnings(&self,
+                               doc_id: DocId,
+                               external_id: Option<&#ext_id_type>,
+                               outcome: &ValidationOutcome) {
+                if let (Some(ref sink), &ValidationOutcome::AcceptWithWarnings(ref warnings)) =
+                    (self.warning_sink.as_ref(), outcome) {
+                    sink(doc_id, external_id, warnings);
+                }
+            }
+
+            /// Only the first `max` whitespace separated tokens of a document are
+            /// indexed. Documents exceeding that are reported in `overflows` and
+            /// to the warning sink.
+            ///
+            /// The index keeps nothing of the dropped tokens, so their terms just
+            /// don't match the document. Fields do not index positions and phrase
+            /// queries are rejected by the parser, so no positional check can
+            /// silently fail on a truncated document.
+            pub fn set_max_tokens_per_document(&mut self, max: usize) {
+                self.max_tokens_per_document = Some(max);
+            }
+
+            fn index_document(&mut self,
+                              doc_id: DocId,
+                              external_id: Option<&#ext_id_type>,
+                              document: &[(Cow<str>, Cow<str>)]) {
+                let mut tokens_seen = 0;
+                let mut tokens_dropped = 0;
+                for &(ref field, ref content) in document {
+                    let content: &str = if let Some(max) = self.max_tokens_per_document {
+                        let budget = max.saturating_sub(tokens_seen - tokens_dropped);
+                        let (content, tokens) = truncate_tokens(content, budget);
+                        tokens_seen += tokens;
+                        tokens_dropped += tokens.saturating_sub(budget);
+                        content
+                    } else {
+                        &**content
+                    };
+                    match &**field {
+                        #(#field_names => {
+                            if let Some(ref pipe) = self.#field_pipes {
+                                pipe(doc_id, &mut self.documents, content);
+                            }
+                        })*
+                        // Rejected by `validate_document`
+                        _ => {}
+                    }
+                }
+                if tokens_dropped > 0 {
+                    if let Some(ref sink) = self.warning_sink {
+                        sink(doc_id,
+                             external_id,
+                             &[format!("Only the first {} of {} tokens were indexed",
+                                       tokens_seen - tokens_dropped,
+                                       tokens_seen)]);
+                    }
+                    self.overflows.push(Overflow {
+                        doc_id,
+                        tokens_seen,
+                        tokens_dropped,
+                    });
+                }
+                let tokens_collapsed = self.documents.collapsed_tokens(doc_id);
+                if tokens_collapsed > 0 {
+                    self.collapses.push(Collapse {
+                        doc_id,
+                        tokens_collapsed: tokens_collapsed as usize,
+                    });
+                }
+            }
+
+            #add_document
+
+            #external_id_lookups
+
+            #run_scored_query
+
             #run_query
+
+            /// Returns (term, field, weight) of every operand `query` would run with,
+            /// without decoding any postings.
+            /// Weights are idf based. Terms that are unknown or have a weight of
+            /// zero (e.g. because they occur in every document) are omitted.
+            /// May clauses are included, must and must not clauses are not.
+            ///
+            /// Fields of one index may have different term types, so there is no
+            /// single term type to return. Terms are returned in their `Display`
+            /// form next to the name of the field they were resolved in.
+            pub fn query_term_weights<'a>(&'a self, query: &Query<'a>)
+                                          -> Vec<(String, String, f32)> {
+                self.resolve_query(query).0
+                    .iter()
+                    .map(|op| {
+                        let op = op.inner();
+                        (op.term().to_string(), op.field().to_string(), op.weight().0)
+                    })
+                    .collect()
+            }
+
+            /// Approximates the number of documents an exhaustive run of `query`
+            /// yields with a HyperLogLog sketch of 2^precision registers.
+            /// See `approx_count_union`.
+            pub fn approx_count<'a>(&'a self, query: Query<'a>, precision: u8) -> ApproxCount {
+                let (ops, filters, excluded) = self.resolve_query(&query);
+                approx_count_union(ops, filters, excluded, precision)
+            }
         }
     )
 }
 
+fn add_document(ast: &syn::MacroInput) -> quote::Tokens {
+    if let Some(ext_id_type) = get_external_id_type(&ast.attrs) {
+        let add_documents = add_documents(quote!((#ext_id_type, D)),
+                                          quote!((external_id, document)),
+                                          quote!(Some(&external_id)),
+                                          quote!(self.store_external_id(doc_id, external_id);));
+        quote!{
+            #add_documents
+
+            /// Validates and indexes a document.
+            /// Rejected documents are neither assigned a DocId nor indexed.
+            ///
+            /// Adding an external id again supersedes its older document:
+            /// `doc_id_for_external` returns the new DocId. The older document
+            /// stays indexed, queries hide it with `Query::dedupe_by_external_id`.
+            pub fn add_document(&mut self,
+                                external_id: #ext_id_type,
+                                document: &[(Cow<str>, Cow<str>)])
+                                -> ValidationOutcome {
+                let outcome = self.validate_document(document);
+                if outcome.is_reject() {
+                    return outcome;
+                }
+                self.doc_counter.inc();
+                let doc_id = self.doc_counter;
+                self.index_document(doc_id, Some(&external_id), document);
+                self.report_warnings(doc_id, Some(&external_id), &outcome);
+                self.store_external_id(doc_id, external_id);
+                outcome
+            }
+
+            fn store_external_id(&mut self, doc_id: DocId, external_id: #ext_id_type) {
+                self.external_doc_ids.insert(external_id.clone(), doc_id);
+                self.external_ids.push((doc_id, external_id));
+            }
+        }
+    } else {
+        let add_documents = add_documents(quote!(D), quote!(document), quote!(None), quote!());
+        quote!{
+            #add_documents
+
+            /// Validates and indexes a document.
+            /// Rejected documents are neither assigned a DocId nor indexed.
+            pub fn add_document(&mut self, document: &[(Cow<str>, Cow<str>)])
+                                -> ValidationOutcome {
+                let outcome = self.validate_document(document);
+                if outcome.is_reject() {
+                    return outcome;
+                }
+                self.doc_counter.inc();
+                let doc_id = self.doc_counter;
+                self.index_document(doc_id, None, document);
+                self.report_warnings(doc_id, None, &outcome);
+                outcome
+            }
+        }
+    }
+}
+
+/// `item` is the type documents are passed as, `pattern` destructures it into
+/// at least `document`. `external_id` evaluates to the document's external id
+/// as an `Option`. `store` runs after indexing with `doc_id` in scope.
+fn add_documents(item: quote::Tokens,
+                 pattern: quote::Tokens,
+                 external_id: quote::Tokens,
+                 store: quote::Tokens)
+                 -> quote::Tokens {
+    quote!{
+        /// Validates and indexes `documents` and commits once at the end.
+        /// Returns the outcome of every document in input order. Rejected
+        /// documents are skipped, the others are indexed like by `add_document`.
+        ///
+        /// Terms are buffered per field and indexed grouped by term on commit.
+        /// See `Field::start_batch`.
+        ///
+        /// Fails only if persisting the metadata fails. The fields are
+        /// committed in that case anyway.
+        pub fn add_documents<'b, I, D>(&mut self, documents: I) -> io::Result<Vec<BatchOutcome>>
+            where I: IntoIterator<Item = #item>,
+                  D: AsRef<[(Cow<'b, str>, Cow<'b, str>)]>
+        {
+            let mut outcomes = Vec::new();
+            self.documents.start_batch();
+            for #pattern in documents {
+                let document = document.as_ref();
+                let outcome = self.validate_document(document);
+                if outcome.is_reject() {
+                    outcomes.push(BatchOutcome {
+                        doc_id: None,
+                        outcome,
+                    });
+                    continue;
+                }
+                self.doc_counter.inc();
+                let doc_id = self.doc_counter;
+                self.index_document(doc_id, #external_id, document);
+                self.report_warnings(doc_id, #external_id, &outcome);
+                #store
+                outcomes.push(BatchOutcome {
+                    doc_id: Some(doc_id),
+                    outcome,
+                });
+            }
+            self.commit()?;
+            Ok(outcomes)
+        }
+    }
+}
+
+/// Returns the identifier and term type of every field that is not marked `no_pipe`
+fn piped_fields(ast: &syn::MacroInput) -> Vec<(syn::Ident, syn::Ty)> {
+    let fields = if let syn::Body::Struct(ref variant_data) = ast.body {
+        variant_data.fields()
+    } else {
+        panic!("derive(PerlinDocument) only implemented for Structs!");
+    };
+    fields.iter()
+        .filter(|field| !field.attrs.iter().any(|attr| attr.name() == "no_pipe"))
+        .map(|field| {
+            (field.ident.clone().expect("derive(PerlinDocument) needs named fields!"),
+             get_term_type(&field.ty).expect("Field type needs a term type parameter!"))
+        })
+        .collect()
+}
+
+fn pipeline_ident(field: &syn::Ident) -> syn::Ident {
+    syn::Ident::from(format!("{}_pipeline", field))
+}
+
+/// Field<T> -> T
+fn get_term_type(ty: &syn::Ty) -> Option<syn::Ty> {
+    if let syn::Ty::Path(_, ref path) = *ty {
+        if let syn::PathParameters::AngleBracketed(ref params) =
+            path.segments.last().unwrap().parameters {
+            return params.types.first().cloned();
+        }
+    }
+    None
+}
+
+/// Runs the query pipeline and resolves the query's clauses
+fn weighting_operator() -> quote::Tokens {
+    quote!{
+        /// Runs the query pipeline and resolves the clauses of `query`.
+        /// Returns the operands, the filters (including `query.filter`) and
+        /// the excluded operands.
+        ///
+        /// If a must clause cannot match (e.g. its term is unknown or the
+        /// query lacks the capability for its field), operands and filters are
+        /// empty, so nothing is yielded. The same holds for a filter on a
+        /// field the query lacks the capability for.
+        ///
+        /// # Panics
+        /// If `query` folds case differently than the index. See `Query::case_folding`
+        fn resolve_query<'a>(&'a self, query: &Query<'a>)
+                             -> (Vec<PeekableSeekable<Operand<'a>>>,
+                                 Vec<PeekableSeekable<Operand<'a>>>,
+                                 Vec<PeekableSeekable<Operand<'a>>>) {
+            if let Some(mismatch) = self.case_folding_mismatch(query) {
+                panic!("Query folds case with {:?}, but the index with {:?}",
+                       mismatch.other,
+                       mismatch.index);
+            }
+            let mut ops = if query.query.is_empty() && !query.clauses.is_empty() {
+                Vec::new()
+            } else if let Some(ref query_pipe) = self.query_pipeline {
+                query_pipe(&self.documents, query)
+            } else {
+                panic!("Query Pipe not set!");
+            };
+            let mut filters = Vec::new();
+            let mut excluded = Vec::new();
+            let mut satisfiable = true;
+            for clause in &query.clauses {
+                match (clause.operator,
+                       self.documents.clause_operand(clause, query)) {
+                    (ChainingOperator::Must, Some(op)) => filters.push(op),
+                    (ChainingOperator::Must, None) => satisfiable = false,
+                    (ChainingOperator::May, Some(op)) => ops.push(op),
+                    (ChainingOperator::MustNot, Some(op)) => excluded.push(op),
+                    _ => {}
+                }
+            }
+            for filter in &query.filter {
+                match self.documents.is_visible(filter.inner().field(), &query.capabilities) {
+                    Some(false) => satisfiable = false,
+                    _ => filters.push(PeekableSeekable::new(filter.inner().clone())),
+                }
+            }
+            if !satisfiable {
+                ops.clear();
+                filters.clear();
+            }
+            (ops, filters, excluded)
+        }
+
+        fn case_folding_mismatch(&self, query: &Query) -> Option<CaseFoldingMismatch> {
+            match (self.case_folding(), query.case_folding) {
+                (Some(index), Some(other)) if index != other => {
+                    Some(CaseFoldingMismatch {
+                        index: index,
+                        other: other,
+                    })
+                }
+                _ => None,
+            }
+        }
+
+        /// Returns why `try_run_query` rejects `query`, if it does
+        fn query_error(&self, query: &Query) -> Option<QueryError> {
+            if let Some(mismatch) = self.case_folding_mismatch(query) {
+                return Some(QueryError::CaseFoldingMismatch(mismatch));
+            }
+            self.restricted_field(query).map(QueryError::RestrictedField)
+        }
+
+        /// Returns the first field targeted by a clause or filter of `query`
+        /// that `query` lacks the capability for
+        fn restricted_field(&self, query: &Query) -> Option<String> {
+            query.clauses
+                .iter()
+                .map(|clause| clause.field.as_str())
+                .chain(query.filter.iter().map(|filter| filter.inner().field()))
+                .find(|field| self.documents.is_visible(field, &query.capabilities) == Some(false))
+                .map(|field| field.to_string())
+        }
+
+        /// Returns the fields `query` lacks the capability for. The query
+        /// pipeline skips them, and clauses and filters on them match nothing.
+        pub fn skipped_fields(&self, query: &Query) -> Vec<String> {
+            self.documents.restricted_fields(&query.capabilities)
+        }
+
+        fn weighting_operator<'a>(&'a self, query: Query<'a>) -> WeightingOperator<'a> {
+            let options = query.weighting_options();
+            let (ops, filters, excluded) = self.resolve_query(&query);
+            WeightingOperator::create_with_options(ops, filters, options).exclude(excluded)
+        }
+    }
+}
+
 fn run_query(ast: &syn::MacroInput) -> quote::Tokens {
     if let Some(ext_id_type) = get_external_id_type(&ast.attrs) {
         quote!{
             pub fn run_query<'a>(&'a self, query: Query<'a>) ->
                 QueryResultIterator<'a, #ext_id_type> {
-                use perlin_core::index::posting::Posting;
-                    if let Some(ref query_pipe) = self.query_pipeline {
-                        let ops = query_pipe(&self.documents, &query);
-                    QueryResultIterator::new(ops, query.filter, &self.external_ids)
-                } else {
-                    panic!("Query Pipe not set!");
+                let dedupe = query.dedupe();
+                QueryResultIterator::from_operator(self.weighting_operator(query),
+                                                   &self.external_ids)
+                    .newest_doc_ids(&self.external_doc_ids)
+                    .dedupe_by_external_id(dedupe)
+            }
+
+            /// Runs `query` granting it `capabilities`
+            pub fn run_query_as<'a>(&'a self, query: Query<'a>, capabilities: &[&str])
+                                    -> QueryResultIterator<'a, #ext_id_type> {
+                self.run_query(query.with_capabilities(capabilities))
+            }
+
+            /// Like `run_query`, but fails if a clause or filter targets a
+            /// field `query` lacks the capability for, instead of matching
+            /// nothing. Also fails instead of panicking if `query` folds case
+            /// differently than the index.
+            pub fn try_run_query<'a>(&'a self, query: Query<'a>)
+                                     -> Result<QueryResultIterator<'a, #ext_id_type>,
+                                               QueryError> {
+                match self.query_error(&query) {
+                    Some(err) => Err(err),
+                    None => Ok(self.run_query(query)),
                 }
             }
+
+            /// Returns at most the first `k` results of `query` in the order
+            /// `run_query` yields them. Results are not scored; use
+            /// `run_scored_query` for the best `k` by BM25.
+            /// The operator stops after `k` postings (see `Query::limit`),
+            /// unless the query dedupes by external id.
+            /// Excluded operands are still decoded in full.
+            pub fn run_query_first_k<'a>(&'a self, query: Query<'a>, k: usize)
+                                       -> Vec<#ext_id_type> {
+                // Dedupe drops postings, so the first k postings may yield
+                // fewer than k results
+                let query = if query.dedupe().is_some() {
+                    query
+                } else {
+                    query.limit(k)
+                };
+                self.run_query(query).take(k).collect()
+            }
         }
     } else {
         quote!{
-            pub fn run_query<'a>(&'a self, query: Query<'a>) -> Operand<'a> {
-                if let Some(ref query_pipe) = self.query_pipeline {
-                    query_pipe(&self.documents, query)
-                } else {
-                    panic!("Query Pipe not set!");
+            pub fn run_query<'a>(&'a self, query: Query<'a>) -> WeightingOperator<'a> {
+                self.weighting_operator(query)
+            }
+
+            /// Runs `query` granting it `capabilities`
+            pub fn run_query_as<'a>(&'a self, query: Query<'a>, capabilities: &[&str])
+                                    -> WeightingOperator<'a> {
+                self.run_query(query.with_capabilities(capabilities))
+            }
+
+            /// Like `run_query`, but fails if a clause or filter targets a
+            /// field `query` lacks the capability for, instead of matching
+            /// nothing. Also fails instead of panicking if `query` folds case
+            /// differently than the index.
+            pub fn try_run_query<'a>(&'a self, query: Query<'a>)
+                                     -> Result<WeightingOperator<'a>, QueryError> {
+                match self.query_error(&query) {
+                    Some(err) => Err(err),
+                    None => Ok(self.run_query(query)),
                 }
             }
+
+            /// Returns at most the first `k` results of `query` in the order
+            /// `run_query` yields them. Results are not scored; use
+            /// `run_scored_query` for the best `k` by BM25.
+            /// The operator stops after `k` postings (see `Query::limit`).
+            /// Excluded operands are still decoded in full.
+            pub fn run_query_first_k<'a>(&'a self, query: Query<'a>, k: usize) -> Vec<Posting> {
+                self.run_query(query.limit(k)).collect()
+            }
+        }
+    }
+}
+
+
+fn run_scored_query(ast: &syn::MacroInput) -> quote::Tokens {
+    let fields = if let syn::Body::Struct(ref variant_data) = ast.body {
+        variant_data.fields()
+    } else {
+        panic!("derive(PerlinDocument) only implemented for Structs!");
+    };
+    // Members of type Field are matched by their name
+    // Members of type Fields are asked for a field with that name
+    let single_fields = fields.iter()
+        .filter(|field| type_name(&field.ty) == Some("Field"))
+        .map(|field| field.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let single_field_names = single_fields.iter().map(|ident| ident.to_string());
+    let multi_fields = fields.iter()
+        .filter(|field| type_name(&field.ty) == Some("Fields"))
+        .map(|field| field.ident.clone().unwrap());
+    quote!{
+        /// Runs `query` and returns the best `k` documents by BM25 score
+        /// See `ScoredOperator`
+        pub fn run_scored_query<'a>(&'a self, query: Query<'a>, k: usize, params: Bm25)
+                                    -> Vec<(Posting, f32)> {
+            let (ops, filters, excluded) = self.resolve_query(&query);
+            let relative_length = |field: &str, doc_id: DocId| {
+                let length = match field {
+                    #(#single_field_names => self.documents.#single_fields
+                                                .relative_doc_length(doc_id),)*
+                    _ => None #(.or_else(|| {
+                        self.documents.#multi_fields
+                            .get(field)
+                            .and_then(|f| f.relative_doc_length(doc_id))
+                    }))*,
+                };
+                length.unwrap_or(1.)
+            };
+            ScoredOperator::create(ops, filters, excluded, k, params, relative_length).collect()
         }
     }
 }
 
+fn type_name(ty: &syn::Ty) -> Option<&str> {
+    if let syn::Ty::Path(_, ref path) = *ty {
+        path.segments.last().map(|segment| segment.ident.as_ref())
+    } else {
+        None
+    }
+}
+
+fn external_id_lookups(ast: &syn::MacroInput) -> quote::Tokens {
+    if let Some(ext_id_type) = get_external_id_type(&ast.attrs) {
+        quote!{
+            /// Returns the external id `doc_id` was indexed with
+            pub fn external_id_for_doc(&self, doc_id: DocId) -> Option<&#ext_id_type> {
+                self.external_ids
+                    .binary_search_by_key(&doc_id, |&(d_id, _)| d_id)
+                    .ok()
+                    .map(|index| &self.external_ids[index].1)
+            }
+
+            /// Returns the DocId of the document indexed with `external_id`
+            pub fn doc_id_for_external(&self, external_id: &#ext_id_type) -> Option<DocId> {
+                self.external_doc_ids.get(external_id).cloned()
+            }
+
+            /// Looks up the external ids of many DocIds at once, e.g. to hydrate a
+            /// page of results. The result is in the order of `doc_ids`.
+            pub fn lookup_external_ids(&self, doc_ids: &[DocId]) -> Vec<Option<&#ext_id_type>> {
+                lookup_external_ids(&self.external_ids, doc_ids)
+            }
+        }
+    } else {
+        quote!()
+    }
+}
 
 fn create_external_ids(ast: &syn::MacroInput) -> quote::Tokens {
     if let Some(_) = get_external_id_type(&ast.attrs) {
         quote!{
-            external_ids: Vec::new()
+            external_ids: Vec::new(),
+            external_doc_ids: HashMap::new()
         }
     } else {
         quote!()
@@ -81,7 +684,10 @@ fn create_external_ids(ast: &syn::MacroInput) -> quote::Tokens {
 
 fn external_id_field(ast: &syn::MacroInput) -> quote::Tokens {
     if let Some(ext_id) = get_external_id_type(&ast.attrs) {
-        quote!(pub external_ids: Vec<(DocId, #ext_id)>,)
+        quote!(
+            pub external_ids: Vec<(DocId, #ext_id)>,
+            pub external_doc_ids: HashMap<#ext_id, DocId>,
+        )
     } else {
         quote!()
     }
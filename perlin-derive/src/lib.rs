@@ -13,7 +13,7 @@ use perlin_document::generate_perlin_document_impl;
 
 use proc_macro::TokenStream;
 
-#[proc_macro_derive(PerlinDocument, attributes(ExternalId, no_pipe, filter))]
+#[proc_macro_derive(PerlinDocument, attributes(ExternalId, no_pipe, filter, shared_vocabulary))]
 pub fn perlin_document(input: TokenStream) -> TokenStream {
     // Standard procedure when it comes to custom derive
     // See https://doc.rust-lang.org/book/procedural-macros.html
@@ -50,16 +50,42 @@ fn impl_perlin_document(ast: &syn::MacroInput) -> quote::Tokens {
             use super::*;
 
             use std::path::{Path, PathBuf};
+            use std::io;
             use std::borrow::Cow;
+            use std::collections::HashMap;
             
             use_parent_crate!(document_index::Pipeline);
             use_parent_crate!(document_index::QueryPipeline);
             use_parent_crate!(document_index::QueryResultIterator);
+            use_parent_crate!(document_index::Overflow);
+            use_parent_crate!(document_index::Collapse);
+            use_parent_crate!(document_index::BatchOutcome);
+            use_parent_crate!(document_index::truncate_tokens);
+            use_parent_crate!(document_index::lookup_external_ids);
             use_parent_crate!(query::Operand);
             use_parent_crate!(query::Query);
+            use_parent_crate!(query::ScoredOperator);
+            use_parent_crate!(query::Bm25);
+            use_parent_crate!(query::WeightingOperator);
+            use_parent_crate!(query::ChainingOperator);
+            use_parent_crate!(query::resolve_clause);
+            use_parent_crate!(query::Clause);
+            use_parent_crate!(query::QueryError);
+            use_parent_crate!(query::ApproxCount);
+            use_parent_crate!(query::approx_count_union);
+            use_parent_crate!(validation::Validator);
+            use_parent_crate!(validation::ValidationOutcome);
+            use_parent_crate!(validation::WarningSink);
+            use_parent_crate!(metadata::Metadata);
+            use_parent_crate!(metadata::CASE_FOLDING_KEY);
+            use_parent_crate!(language::CaseFolding);
+            use_parent_crate!(language::CaseFoldingMismatch);
             
-            use perlin_core::index::posting::{PostingIterator, DocId};
-            use perlin_core::index::vocabulary::TermId;
+            use_parent_crate!(perlin_core::index::posting::Posting);
+            use_parent_crate!(perlin_core::index::posting::PostingIterator);
+            use_parent_crate!(perlin_core::index::posting::DocId);
+            use_parent_crate!(perlin_core::index::vocabulary::TermId);
+            use_parent_crate!(perlin_core::utils::seeking_iterator::PeekableSeekable);
             
             #index_struct
 
@@ -10,6 +10,35 @@ pub fn generate_perlin_document_impl(ast: &syn::MacroInput) -> quote::Tokens {
     };
     let index_creations = generate_index_creations(variant_data.fields());
     let fields = variant_data.fields().iter().map(|f| f.ident.clone());
+    let batch_fields = variant_data.fields().iter().map(|f| f.ident.clone());
+    let clause_fields = variant_data.fields()
+        .iter()
+        .filter(|f| get_type_ident(&f.ty).map(|ty| ty == "Field").unwrap_or(false))
+        .map(|f| f.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let clause_arms = clause_fields.iter().map(|field| {
+        let name = field.to_string();
+        quote!(
+            #name => resolve_clause(&self.#field, clause, query),
+        )
+    });
+    let visibility_arms = clause_fields.iter().map(|field| {
+        let name = field.to_string();
+        quote!(
+            #name => Some(self.#field.is_visible_to(capabilities)),
+        )
+    });
+    let multi_fields = variant_data.fields()
+        .iter()
+        .filter(|f| get_type_ident(&f.ty).map(|ty| ty == "Fields").unwrap_or(false))
+        .map(|f| f.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let visibility_multi_fields = multi_fields.iter();
+    let restricted_fields = clause_fields.iter();
+    let restricted_names = clause_fields.iter().map(|field| field.to_string());
+    let restricted_multi_fields = multi_fields.iter();
+    let collapsed_fields = clause_fields.iter();
+    let collapsed_multi_fields = multi_fields.iter();
     
     quote!(        
         impl #ident {            
@@ -19,9 +48,62 @@ pub fn generate_perlin_document_impl(ast: &syn::MacroInput) -> quote::Tokens {
                 }
             }
 
+            /// Commits all fields in the order they are declared
             pub fn commit(&mut self) {
                 #(self.#fields.commit();)*                
             }
+
+            /// Starts a batch on all fields. See `Field::start_batch`
+            pub fn start_batch(&mut self) {
+                #(self.#batch_fields.start_batch();)*
+            }
+
+            /// Returns the operand for `clause`.
+            /// `None` if there is no such field or `resolve_clause` returns
+            /// `None` for it.
+            pub fn clause_operand<'a>(&'a self, clause: &Clause, query: &Query)
+                                      -> Option<PeekableSeekable<Operand<'a>>> {
+                match clause.field.as_str() {
+                    #(#clause_arms)*
+                    _ => None,
+                }
+            }
+
+            /// Returns whether the field `name` is visible to `capabilities`.
+            /// `None` if there is no such field.
+            pub fn is_visible(&self, name: &str, capabilities: &[String]) -> Option<bool> {
+                match name {
+                    #(#visibility_arms)*
+                    _ => None #(.or_else(|| {
+                        self.#visibility_multi_fields
+                            .get(name)
+                            .map(|field| field.is_visible_to(capabilities))
+                    }))*,
+                }
+            }
+
+            /// Returns the number of tokens of `doc_id` dropped as repeats,
+            /// summed over all fields. See `Field::collapsed_tokens`
+            pub fn collapsed_tokens(&self, doc_id: DocId) -> u32 {
+                0 #(+ self.#collapsed_fields.collapsed_tokens(doc_id))*
+                  #(+ self.#collapsed_multi_fields
+                      .iter()
+                      .map(|field| field.collapsed_tokens(doc_id))
+                      .sum::<u32>())*
+            }
+
+            /// Returns the names of all fields not visible to `capabilities`
+            pub fn restricted_fields(&self, capabilities: &[String]) -> Vec<String> {
+                let mut result = Vec::new();
+                #(if !self.#restricted_fields.is_visible_to(capabilities) {
+                    result.push(#restricted_names.to_string());
+                })*
+                #(result.extend(self.#restricted_multi_fields
+                    .iter()
+                    .filter(|field| !field.is_visible_to(capabilities))
+                    .map(|field| field.name().to_string()));)*
+                result
+            }
         }
     )
 }
@@ -31,9 +113,15 @@ fn generate_index_creations(fields: &[syn::Field]) -> Vec<quote::Tokens> {
     for field in fields {
         let type_ident = get_type_ident(&field.ty).unwrap();
         let ident = &field.ident;
-        result.push(quote!(
-            #ident: #type_ident::new()
-        ));            
+        if field.attrs.iter().any(|attr| attr.name() == "shared_vocabulary") {
+            result.push(quote!(
+                #ident: #type_ident::new_shared()
+            ));
+        } else {
+            result.push(quote!(
+                #ident: #type_ident::new()
+            ));
+        }
     }
     result
 }
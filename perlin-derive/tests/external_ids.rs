@@ -0,0 +1,64 @@
+#[macro_use]
+extern crate perlin;
+#[macro_use]
+extern crate perlin_derive;
+
+use std::env::temp_dir;
+use std::fs;
+use std::borrow::Cow;
+
+use perlin::perlin_core::index::posting::DocId;
+use perlin::field::Field;
+use perlin::language::{LowercaseFilter, WhitespaceTokenizer};
+
+#[derive(PerlinDocument)]
+#[ExternalId(u64)]
+pub struct Planet {
+    text: Field<String>,
+}
+
+fn create_index(name: &str) -> PlanetIndex {
+    let path = temp_dir().join("perlin_derive_tests").join(name);
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
+    fs::create_dir_all(&path).unwrap();
+    let mut index = PlanetIndex::create(path).unwrap();
+    index.set_text_pipeline(pipeline!(text WhitespaceTokenizer > LowercaseFilter));
+    index.set_query_pipeline(query_pipeline!(WhitespaceTokenizer
+                                             > LowercaseFilter
+                                             > [All in text]));
+    index
+}
+
+fn doc(text: &str) -> Vec<(Cow<str>, Cow<str>)> {
+    vec![(Cow::from("text"), Cow::from(text))]
+}
+
+#[test]
+fn lookups_both_ways() {
+    let mut index = create_index("lookups_both_ways");
+    index.add_document(1, &doc("ceres"));
+    index.add_documents(vec![(4, doc("vesta")), (2, doc("pallas"))]).unwrap();
+    assert_eq!(index.doc_id_for_external(&1), Some(DocId(0)));
+    assert_eq!(index.doc_id_for_external(&4), Some(DocId(1)));
+    assert_eq!(index.doc_id_for_external(&2), Some(DocId(2)));
+    assert_eq!(index.doc_id_for_external(&3), None);
+    assert_eq!(index.external_id_for_doc(DocId(1)), Some(&4));
+    assert_eq!(index.external_id_for_doc(DocId(3)), None);
+    assert_eq!(index.lookup_external_ids(&[DocId(2), DocId(7), DocId(0)]),
+               vec![Some(&2), None, Some(&1)]);
+}
+
+#[test]
+fn many_documents() {
+    let mut index = create_index("many_documents");
+    let docs = (0..10_000u64).map(|i| (i * 3, doc("asteroid"))).collect::<Vec<_>>();
+    index.add_documents(docs).unwrap();
+    for i in (0..10_000u64).filter(|i| i % 97 == 0) {
+        let doc_id = index.doc_id_for_external(&(i * 3)).unwrap();
+        assert_eq!(doc_id, DocId(i as u32));
+        assert_eq!(index.external_id_for_doc(doc_id), Some(&(i * 3)));
+    }
+    assert_eq!(index.doc_id_for_external(&1), None);
+}
@@ -1,6 +1,10 @@
 use std::marker::PhantomData;
+use std::hash::Hash;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
-use query::{Query, Operand, WeightingOperator};
+use query::{Query, Operand, WeightingOperator, WeightingOptions, DedupePolicy};
+use validation::ValidationOutcome;
 use perlin_core::utils::seeking_iterator::PeekableSeekable;
 use perlin_core::index::posting::{Posting, DocId};
 
@@ -8,42 +12,192 @@ pub type Pipeline<Out, T> = Box<Fn(DocId, &mut T, &str) -> PhantomData<Out> + Sy
 pub type QueryPipeline<T> =
     Box<for<'r> Fn(&'r T, &Query<'r>) -> Vec<PeekableSeekable<Operand<'r>>> + Sync + Send>;
 
-pub struct QueryResultIterator<'a, T: 'a>(WeightingOperator<'a>, &'a [(DocId, T)]);
+/// Reported by the derived index when a document exceeded the maximum number
+/// of tokens per document. Only the first tokens are indexed in that case.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overflow {
+    pub doc_id: DocId,
+    pub tokens_seen: usize,
+    pub tokens_dropped: usize,
+}
+
+/// Reported by the derived index for a document in which a `RepeatCollapser`
+/// dropped tokens. See `Field::collapsed_tokens`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collapse {
+    pub doc_id: DocId,
+    pub tokens_collapsed: usize,
+}
+
+/// Returned by the derived index's `add_documents` for every document of the
+/// batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchOutcome {
+    /// `None` if the document was rejected
+    pub doc_id: Option<DocId>,
+    pub outcome: ValidationOutcome,
+}
 
-impl<'a, T: 'a + Clone> QueryResultIterator<'a, T> {
+/// Cuts `content` after `max` whitespace separated tokens.
+/// Returns the remaining content and the number of tokens in the original.
+///
+/// Tokens are estimated by whitespace, independently of the tokenizer used
+/// in the pipeline.
+pub fn truncate_tokens(content: &str, max: usize) -> (&str, usize) {
+    let mut end = content.len();
+    let mut tokens = 0;
+    let mut in_token = false;
+    for (i, c) in content.char_indices() {
+        if c.is_whitespace() {
+            in_token = false;
+        } else if !in_token {
+            in_token = true;
+            if tokens == max {
+                end = i;
+            }
+            tokens += 1;
+        }
+    }
+    if tokens > max {
+        (&content[..end], tokens)
+    } else {
+        (content, tokens)
+    }
+}
+
+/// Looks up the external ids of `doc_ids` in `ext_ids`, which is sorted by DocId
+///
+/// The requested ids are sorted internally so the mapping is walked only once.
+/// The result is in the order of `doc_ids`. Unknown DocIds yield `None`.
+pub fn lookup_external_ids<'a, T>(ext_ids: &'a [(DocId, T)],
+                                  doc_ids: &[DocId])
+                                  -> Vec<Option<&'a T>> {
+    let mut order = (0..doc_ids.len()).collect::<Vec<_>>();
+    order.sort_by_key(|i| doc_ids[*i]);
+    let mut result = vec![None; doc_ids.len()];
+    let mut pos = 0;
+    for i in order {
+        let doc_id = doc_ids[i];
+        while pos < ext_ids.len() && ext_ids[pos].0 < doc_id {
+            pos += 1;
+        }
+        if pos < ext_ids.len() && ext_ids[pos].0 == doc_id {
+            result[i] = Some(&ext_ids[pos].1);
+        }
+    }
+    result
+}
+
+pub struct QueryResultIterator<'a, T: 'a + Clone + Hash + Eq> {
+    operator: WeightingOperator<'a>,
+    ext_ids: &'a [(DocId, T)],
+    dedupe: Option<DedupePolicy>,
+    // Newest DocId per external id. Needed for `DedupePolicy::Newest`
+    newest: Option<Cow<'a, HashMap<T, DocId>>>,
+    emitted: HashSet<T>,
+    suppressed: usize,
+}
+
+impl<'a, T: 'a + Hash + Eq + Clone> QueryResultIterator<'a, T> {
     pub fn new(ops: Vec<PeekableSeekable<Operand<'a>>>,
                filters: Vec<PeekableSeekable<Operand<'a>>>,
                ext_ids: &'a [(DocId, T)])
                -> Self {
-        QueryResultIterator(WeightingOperator::create(ops, filters), ext_ids)
+        Self::with_options(ops, filters, WeightingOptions::default(), ext_ids)
+    }
+
+    pub fn with_options(ops: Vec<PeekableSeekable<Operand<'a>>>,
+                        filters: Vec<PeekableSeekable<Operand<'a>>>,
+                        options: WeightingOptions,
+                        ext_ids: &'a [(DocId, T)])
+                        -> Self {
+        Self::from_operator(WeightingOperator::create_with_options(ops, filters, options),
+                            ext_ids)
+    }
+
+    pub fn from_operator(operator: WeightingOperator<'a>, ext_ids: &'a [(DocId, T)]) -> Self {
+        QueryResultIterator {
+            operator,
+            ext_ids,
+            dedupe: None,
+            newest: None,
+            emitted: HashSet::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Yields only one document per external id. See `DedupePolicy`.
+    ///
+    /// Memory is bounded by the number of distinct external ids emitted.
+    /// `DedupePolicy::Newest` additionally needs the newest DocId of every
+    /// external id. Without `newest_doc_ids` it is built from `ext_ids` once.
+    pub fn dedupe_by_external_id(mut self, policy: Option<DedupePolicy>) -> Self {
+        if policy == Some(DedupePolicy::Newest) && self.newest.is_none() {
+            let mut newest = HashMap::new();
+            for &(doc_id, ref ext_id) in self.ext_ids {
+                newest.insert(ext_id.clone(), doc_id);
+            }
+            self.newest = Some(Cow::Owned(newest));
+        }
+        self.dedupe = policy;
+        self
+    }
+
+    /// Sets the newest DocId of every external id, e.g. the derived index's
+    /// `external_doc_ids`
+    pub fn newest_doc_ids(mut self, newest: &'a HashMap<T, DocId>) -> Self {
+        self.newest = Some(Cow::Borrowed(newest));
+        self
+    }
+
+    /// Number of matching documents that were suppressed as duplicates so far
+    pub fn suppressed(&self) -> usize {
+        self.suppressed
+    }
+
+    /// Like `next` but also returns the DocId of the emitted document
+    pub fn next_with_doc_id(&mut self) -> Option<(DocId, T)> {
+        loop {
+            let Posting(doc_id) = self.operator.next()?;
+            let index = match self.ext_ids.binary_search_by_key(&doc_id, |&(d_id, _)| d_id) {
+                Ok(index) => index,
+                Err(_) => panic!("DocId unkown!"),
+            };
+            let ext_id = &self.ext_ids[index].1;
+            if let Some(policy) = self.dedupe {
+                let outdated = match (policy, self.newest.as_ref()) {
+                    (DedupePolicy::Newest, Some(newest)) => newest.get(ext_id) != Some(&doc_id),
+                    _ => false,
+                };
+                let duplicate = outdated || self.emitted.contains(ext_id);
+                if duplicate {
+                    self.suppressed += 1;
+                    continue;
+                }
+                self.emitted.insert(ext_id.clone());
+            }
+            return Some((doc_id, ext_id.clone()));
+        }
     }
 }
 
-impl<'a, T: 'a + Clone> Iterator for QueryResultIterator<'a, T> {
+impl<'a, T: 'a + Hash + Eq + Clone> Iterator for QueryResultIterator<'a, T> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(Posting(doc_id)) = self.0.next() {
-            if let Ok(index) = self.1.binary_search_by_key(&doc_id, |&(d_id, _)| d_id) {
-                Some(self.1[index].1.clone())
-            } else {
-                panic!("DocId unkown!");
-            }
-        } else {
-            None
-        }
+        self.next_with_doc_id().map(|(_, ext_id)| ext_id)
     }
 }
 
-impl<'a, T: 'a> AsRef<WeightingOperator<'a>> for QueryResultIterator<'a, T> {
+impl<'a, T: 'a + Clone + Hash + Eq> AsRef<WeightingOperator<'a>> for QueryResultIterator<'a, T> {
     fn as_ref(&self) -> &WeightingOperator<'a> {
-        &self.0
+        &self.operator
     }
 }
 
-impl<'a, T: 'a> AsMut<WeightingOperator<'a>> for QueryResultIterator<'a, T> {
+impl<'a, T: 'a + Clone + Hash + Eq> AsMut<WeightingOperator<'a>> for QueryResultIterator<'a, T> {
     fn as_mut(&mut self) -> &mut WeightingOperator<'a> {
-        &mut self.0
+        &mut self.operator
     }
 }
 
@@ -55,7 +209,7 @@ mod tests {
 
     use rust_stemmers::Algorithm;
 
-    use test_utils::create_test_dir;
+    use test_utils::{create_test_dir, test_dir};
 
     #[derive(PerlinDocument)]
     pub struct Test {
@@ -72,11 +226,12 @@ mod tests {
     use language::integers::NumberFilter;
     use std::borrow::Cow;
     use perlin_core::index::posting::Posting;
-    use query::{Query, ChainingOperator};
+    use query::{Query, Bm25};
+    use validation::{self, ValidationOutcome};
 
 
     fn create_and_fill_index(name: &str) -> TestIndex {
-        let mut t = TestIndex::create(create_test_dir(name));
+        let mut t = TestIndex::create(create_test_dir(name)).unwrap();
         t.set_text_pipeline(pipeline!(text
                            WhitespaceTokenizer
                            > NumberFilter
@@ -100,7 +255,7 @@ mod tests {
         t.add_document(&[(Cow::from("title"), Cow::from("Unicorns on Deimos")),
                          (Cow::from("text"),
                           Cow::from("2567 unicorns flew from phobos to deimos"))]);
-        t.commit();
+        t.commit().unwrap();
         t
     }
 
@@ -166,14 +321,408 @@ mod tests {
     fn filtered_query() {
         let t = create_and_fill_index("doc_index/filtered_query");
         let unfiltered = Query::new("flew");
-        let filtered =
-            Query::new("flew").filter_by(ChainingOperator::Must,
-                                         t.documents.number.query_atom(&2567));
+        let filtered = Query::new("flew").filter_by(&t.documents.number, &2567);
 
         assert_eq!(t.run_query(unfiltered).collect::<Vec<_>>(),
                    vec![Posting(DocId(0)), Posting(DocId(1)), Posting(DocId(2))]);
         assert_eq!(t.run_query(filtered).collect::<Vec<_>>(),
                    vec![Posting(DocId(2))]);
+        // An unknown term filters out everything
+        let unknown = Query::new("flew").filter_by(&t.documents.number, &42);
+        assert_eq!(t.run_query(unknown).count(), 0);
+    }
+
+    #[test]
+    fn exhaustive_query() {
+        let mut t = create_and_fill_index("doc_index/exhaustive_query");
+        // Make matches in text worth almost nothing
+        t.documents.text.term_doc_ratio = 1000.;
+        let default = t.run_query(Query::new("2567 birds")).collect::<Vec<_>>();
+        let exhaustive = t.run_query(Query::new("2567 birds").exhaustive(true))
+            .collect::<Vec<_>>();
+        assert_eq!(default, vec![Posting(DocId(2))]);
+        assert_eq!(exhaustive,
+                   vec![Posting(DocId(2)), Posting(DocId(0)), Posting(DocId(1))]);
+        assert_eq!(&exhaustive[..default.len()], &default[..]);
+    }
+
+    #[test]
+    fn rejected_document() {
+        let mut t = create_and_fill_index("doc_index/rejected_document");
+        t.set_validator(validation::max_token_count("text", 6));
+        let doc_counter = t.doc_counter;
+        let terms = t.documents.text.iterate_terms().count();
+        let outcome = t.add_document(&[(Cow::from("text"),
+                                        Cow::from("ceres orbits the sun between mars and jupiter"))]);
+        assert!(outcome.is_reject());
+        t.commit().unwrap();
+        assert_eq!(t.doc_counter, doc_counter);
+        assert_eq!(t.documents.text.iterate_terms().count(), terms);
+        should_yield(&t, "ceres", &[]);
+
+        // Fields that are unknown or have no pipeline are rejected up front
+        let outcome = t.add_document(&[(Cow::from("text"), Cow::from("ceres")),
+                                       (Cow::from("color"), Cow::from("grey"))]);
+        assert_eq!(outcome,
+                   ValidationOutcome::Reject { reason: "Unknown field color".to_string() });
+        let mut t = TestIndex::create(create_test_dir("doc_index/rejected_document_pipeline"))
+            .unwrap();
+        let outcome = t.add_document(&[(Cow::from("text"), Cow::from("ceres"))]);
+        assert_eq!(outcome,
+                   ValidationOutcome::Reject {
+                       reason: "Pipeline for field text not set".to_string(),
+                   });
+        assert_eq!(t.doc_counter, DocId::none());
+    }
+
+    #[test]
+    fn batch_validation() {
+        use std::sync::{Arc, Mutex};
+
+        let mut t = create_and_fill_index("doc_index/batch_validation");
+        t.set_validator(validation::all(vec![validation::required_field("text"),
+                                             validation::warn_only(
+                                                 validation::max_field_length("title", 6))]));
+        let docs = vec![vec![(Cow::from("text"), Cow::from("ceres"))],
+                        vec![(Cow::from("title"), Cow::from("Ceres"))],
+                        vec![(Cow::from("title"), Cow::from("Dwarf planets")),
+                             (Cow::from("text"), Cow::from("ceres"))]];
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        t.set_warning_sink(Box::new(move |doc_id, _, warnings| {
+            sink.lock().unwrap().push((doc_id, warnings.to_vec()))
+        }));
+        let outcomes = docs.iter().map(|doc| t.add_document(doc)).collect::<Vec<_>>();
+        t.commit().unwrap();
+        assert_eq!(outcomes[0], ValidationOutcome::Accept);
+        assert!(outcomes[1].is_reject());
+        assert_eq!(outcomes[2],
+                   ValidationOutcome::AcceptWithWarnings(vec![
+                       "Field title is 13 bytes long. Maximum is 6".to_string()]));
+        assert_eq!(*warnings.lock().unwrap(),
+                   vec![(DocId(4),
+                         vec!["Field title is 13 bytes long. Maximum is 6".to_string()])]);
+        should_yield(&t, "ceres", &[3, 4]);
+    }
+
+    #[test]
+    fn first_k() {
+        let t = create_and_fill_index("doc_index/first_k");
+        let all = t.run_query(Query::new("birds")).collect::<Vec<_>>();
+        assert_eq!(all.len(), 2);
+        assert_eq!(t.run_query_first_k(Query::new("birds"), 1), &all[..1]);
+        assert_eq!(t.run_query_first_k(Query::new("birds"), 10), all);
+        assert!(t.run_query_first_k(Query::new("birds"), 0).is_empty());
+    }
+
+    #[test]
+    fn batch_indexing() {
+        use std::sync::{Arc, Mutex};
+        use document_index::BatchOutcome;
+
+        let accepted = |id| {
+            BatchOutcome {
+                doc_id: Some(DocId(id)),
+                outcome: ValidationOutcome::Accept,
+            }
+        };
+        let rejected = |reason: &str| {
+            BatchOutcome {
+                doc_id: None,
+                outcome: ValidationOutcome::Reject { reason: reason.to_string() },
+            }
+        };
+        let mut t = create_and_fill_index("doc_index/batch_indexing");
+        t.set_validator(validation::all(vec![validation::required_field("text"),
+                                             validation::warn_only(
+                                                 validation::max_field_length("title", 6))]));
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        t.set_warning_sink(Box::new(move |doc_id, _, warnings| {
+            sink.lock().unwrap().push((doc_id, warnings.to_vec()))
+        }));
+        let docs = vec![vec![(Cow::from("text"), Cow::from("ceres"))],
+                        vec![(Cow::from("text"), Cow::from("vesta"))],
+                        vec![(Cow::from("title"), Cow::from("Pallas")),
+                             (Cow::from("text"), Cow::from("pallas"))]];
+        assert_eq!(t.add_documents(docs).unwrap(),
+                   vec![accepted(3), accepted(4), accepted(5)]);
+        should_yield(&t, "vesta", &[4]);
+
+        // Rejected documents are reported and skipped, the rest is indexed
+        let docs = vec![vec![(Cow::from("text"), Cow::from("ceres hygiea"))],
+                        vec![(Cow::from("title"), Cow::from("Hygiea"))],
+                        vec![(Cow::from("text"), Cow::from("juno")),
+                             (Cow::from("color"), Cow::from("grey"))],
+                        vec![(Cow::from("title"), Cow::from("Asteroid")),
+                             (Cow::from("text"), Cow::from("hygiea"))]];
+        let warnings_of_7 = vec!["Field title is 8 bytes long. Maximum is 6".to_string()];
+        assert_eq!(t.add_documents(docs).unwrap(),
+                   vec![accepted(6),
+                        rejected("Field text is empty or missing"),
+                        rejected("Unknown field color"),
+                        BatchOutcome {
+                            doc_id: Some(DocId(7)),
+                            outcome: ValidationOutcome::AcceptWithWarnings(warnings_of_7.clone()),
+                        }]);
+        should_yield(&t, "hygiea", &[6, 7]);
+        should_yield(&t, "ceres", &[3, 6]);
+        should_yield(&t, "juno", &[]);
+        assert_eq!(t.doc_counter, DocId(7));
+        assert_eq!(*warnings.lock().unwrap(), vec![(DocId(7), warnings_of_7)]);
+
+        // Batches index the same as single documents
+        let mut single = create_and_fill_index("doc_index/batch_indexing_single");
+        let mut batched = TestIndex::create(create_test_dir("doc_index/batch_indexing_batched"))
+            .unwrap();
+        batched.set_text_pipeline(pipeline!(text WhitespaceTokenizer > LowercaseFilter));
+        single.set_text_pipeline(pipeline!(text WhitespaceTokenizer > LowercaseFilter));
+        let docs = ["ceres vesta", "vesta pallas", "pallas ceres hygiea"];
+        batched.add_documents(docs.iter()
+                .map(|doc| vec![(Cow::from("text"), Cow::from(*doc))]))
+            .unwrap();
+        for doc in &docs {
+            single.add_document(&[(Cow::from("text"), Cow::from(*doc))]);
+        }
+        single.commit().unwrap();
+        for term in &["ceres", "vesta", "pallas", "hygiea"] {
+            let postings = |t: &TestIndex, offset: u32| {
+                let (_, postings) = t.documents.text.query_atom(&term.to_string());
+                postings.map(|Posting(DocId(id))| id - offset).collect::<Vec<_>>()
+            };
+            assert_eq!(postings(&batched, 0), postings(&single, 3));
+        }
+    }
+
+    #[test]
+    fn token_cap() {
+        use std::sync::{Arc, Mutex};
+        use document_index::{truncate_tokens, Overflow};
+        use query::QueryBuilder;
+        use test_utils::count_events;
+        assert_eq!(truncate_tokens(" ceres  orbits the sun", 2), (" ceres  orbits ", 4));
+        assert_eq!(truncate_tokens("ceres orbits", 2), ("ceres orbits", 2));
+
+        let mut t = create_and_fill_index("doc_index/token_cap");
+        t.set_max_tokens_per_document(4);
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        t.set_warning_sink(Box::new(move |doc_id, _, warnings| {
+            sink.lock().unwrap().push((doc_id, warnings.to_vec()))
+        }));
+        t.add_document(&[(Cow::from("title"), Cow::from("Ceres")),
+                         (Cow::from("text"), Cow::from("orbits the sun between mars"))]);
+        t.add_document(&[(Cow::from("text"), Cow::from("vesta orbits"))]);
+        t.commit().unwrap();
+        assert_eq!(t.overflows,
+                   vec![Overflow {
+                            doc_id: DocId(3),
+                            tokens_seen: 6,
+                            tokens_dropped: 2,
+                        }]);
+        assert_eq!(*warnings.lock().unwrap(),
+                   vec![(DocId(3),
+                         vec!["Only the first 4 of 6 tokens were indexed".to_string()])]);
+        should_yield(&t, "sun", &[3]);
+        should_yield(&t, "mars", &[]);
+        should_yield(&t, "vesta", &[4]);
+        // Phrases can't span the cut, they are rejected before querying
+        assert!(QueryBuilder::parse("text", "\"between mars\"").is_err());
+
+        // The pipelines never see more than the cap, however long the document
+        let long = vec!["asteroid"; 10_000].join(" ");
+        let buffered = count_events("put term", || {
+            t.add_document(&[(Cow::from("text"), Cow::from(long.as_str()))]);
+        });
+        assert_eq!(buffered, 4);
+    }
+
+    #[test]
+    fn collapsed_repeats() {
+        use document_index::Collapse;
+        use language::RepeatCollapser;
+
+        let mut t = TestIndex::create(create_test_dir("doc_index/collapsed_repeats")).unwrap();
+        t.set_text_pipeline(pipeline!(text
+                                      WhitespaceTokenizer
+                                      > RepeatCollapser(1)
+                                      > LowercaseFilter));
+        t.add_document(&[(Cow::from("text"), Cow::from("buy buy buy now"))]);
+        t.add_document(&[(Cow::from("text"), Cow::from("ceres"))]);
+        t.add_document(&[(Cow::from("text"), Cow::from("vesta vesta"))]);
+        t.commit().unwrap();
+        assert_eq!(t.collapses,
+                   vec![Collapse {
+                            doc_id: DocId(0),
+                            tokens_collapsed: 2,
+                        },
+                        Collapse {
+                            doc_id: DocId(2),
+                            tokens_collapsed: 1,
+                        }]);
+    }
+
+    #[test]
+    fn batch_external_ids() {
+        use document_index::lookup_external_ids;
+        let ext_ids = (0..50).map(|i| (DocId(i * 2), i)).collect::<Vec<_>>();
+        let single = |doc_id: DocId| {
+            ext_ids.binary_search_by_key(&doc_id, |&(d_id, _)| d_id).ok().map(|i| &ext_ids[i].1)
+        };
+        // Pseudo random ids including unknown ones and duplicates
+        let doc_ids = (0..200).map(|i| DocId((i * 7919) % 113)).collect::<Vec<_>>();
+        assert_eq!(lookup_external_ids(&ext_ids, &doc_ids),
+                   doc_ids.iter().map(|d| single(*d)).collect::<Vec<_>>());
+        assert_eq!(lookup_external_ids(&ext_ids, &[DocId(8), DocId(3), DocId(2)]),
+                   vec![Some(&4), None, Some(&1)]);
+        assert!(lookup_external_ids(&ext_ids, &[]).is_empty());
+    }
+
+    #[test]
+    fn query_term_weights() {
+        let t = create_and_fill_index("doc_index/query_term_weights");
+        let weights = t.query_term_weights(&Query::new("flew birds ocean"));
+        let weight = |term: &str| {
+            weights.iter()
+                .find(|&&(ref t, ref field, _)| t == term && field == "text")
+                .map(|&(_, _, w)| w)
+                .unwrap_or(0.)
+        };
+        assert!(weight("ocean") > weight("bird"));
+        assert!(weight("bird") > weight("flew"));
+        assert!(weight("flew") < 0.01 * weight("ocean") + ::std::f32::EPSILON);
+    }
+
+    #[test]
+    fn approx_count() {
+        let t = create_and_fill_index("doc_index/approx_count");
+        let count = |query| t.approx_count(query, 10).estimate.round() as usize;
+        assert_eq!(count(Query::new("flew")), 3);
+        assert_eq!(count(Query::new("birds ocean")),
+                   t.run_query(Query::new("birds ocean").exhaustive(true)).count());
+        assert_eq!(count(Query::new("comet")), 0);
+
+        // Without cached sketches every posting is decoded, but never more
+        // often than by an exhaustive run
+        use test_utils::count_events;
+        for query in &["flew", "birds ocean", "birds ocean flew"] {
+            let approx = count_events("decoded posting",
+                                      || { t.approx_count(Query::new(query), 10); });
+            let exact = count_events("decoded posting", || {
+                t.run_query(Query::new(query).exhaustive(true)).count();
+            });
+            assert!(approx > 0);
+            assert!(approx <= exact, format!("{}: {} > {}", query, approx, exact));
+        }
+    }
+
+    #[test]
+    fn restricted_field() {
+        let mut t = create_and_fill_index("doc_index/restricted_field");
+        t.documents.text.required_capability = Some("staff".to_string());
+        // Only the number field is visible without the capability
+        should_yield(&t, "deimos", &[]);
+        should_yield(&t, "2567", &[2]);
+        assert_eq!(t.run_query_as(Query::new("deimos"), &["guest"]).collect::<Vec<_>>(),
+                   vec![]);
+        assert_eq!(t.run_query_as(Query::new("deimos"), &["guest", "staff"])
+                       .collect::<Vec<_>>(),
+                   vec![Posting(DocId(2))]);
+        assert!(t.query_term_weights(&Query::new("deimos")).is_empty());
+
+        // Clauses on the field are restricted the same way
+        use query::QueryBuilder;
+        let must = || QueryBuilder::new("").must("text", "unicorn").build();
+        assert_eq!(t.run_query(must()).collect::<Vec<_>>(), vec![]);
+        assert_eq!(t.run_query_as(must(), &["staff"]).collect::<Vec<_>>(),
+                   vec![Posting(DocId(2))]);
+        let may = QueryBuilder::new("2567").may("text", "bird").build();
+        assert_eq!(t.run_query(may).collect::<Vec<_>>(), vec![Posting(DocId(2))]);
+        assert!(t.query_term_weights(&QueryBuilder::new("").may("text", "bird").build())
+            .is_empty());
+
+        // And so are filters
+        let filtered = || Query::new("2567").filter_by(&t.documents.text, &"unicorn".to_string());
+        assert_eq!(t.run_query(filtered()).count(), 0);
+        assert_eq!(t.run_query_as(filtered(), &["staff"]).collect::<Vec<_>>(),
+                   vec![Posting(DocId(2))]);
+
+        // The error mode names the field instead
+        use query::QueryError;
+        assert_eq!(t.try_run_query(filtered()).err(),
+                   Some(QueryError::RestrictedField("text".to_string())));
+        assert_eq!(t.try_run_query(must()).err(),
+                   Some(QueryError::RestrictedField("text".to_string())));
+        assert!(t.try_run_query(filtered().with_capabilities(&["staff"])).is_ok());
+        assert!(t.try_run_query(Query::new("deimos")).is_ok());
+        assert_eq!(t.skipped_fields(&Query::new("deimos")), vec!["text".to_string()]);
+        assert!(t.skipped_fields(&Query::new("deimos").with_capabilities(&["staff"])).is_empty());
+    }
+
+    #[test]
+    fn case_folding() {
+        use language::{CaseFolder, CaseFolding, CaseFoldingMismatch};
+        use metadata::{Metadata, CASE_FOLDING_KEY};
+        use query::QueryError;
+
+        let path = create_test_dir("doc_index/case_folding");
+        let mut t = TestIndex::create(path.clone()).unwrap();
+        t.set_text_pipeline(pipeline!(text
+                                      WhitespaceTokenizer
+                                      > CaseFolder(CaseFolding::Turkish)));
+        t.set_query_pipeline(query_pipeline!(WhitespaceTokenizer
+                                             > CaseFolder(CaseFolding::Turkish)
+                                             > [All in text]));
+        t.set_case_folding(CaseFolding::Turkish).unwrap();
+        t.add_document(&[(Cow::from("text"), Cow::from("İstanbul"))]);
+        t.commit().unwrap();
+        assert_eq!(Metadata::load(&path.join("metadata.bin")).unwrap().get(CASE_FOLDING_KEY),
+                   Some(&b"turkish"[..]));
+
+        let query = |mode| Query::new("İSTANBUL").case_folding(mode);
+        assert_eq!(t.run_query(query(CaseFolding::Turkish)).collect::<Vec<_>>(),
+                   vec![Posting(DocId(0))]);
+        assert_eq!(t.try_run_query(query(CaseFolding::Unicode)).err(),
+                   Some(QueryError::CaseFoldingMismatch(CaseFoldingMismatch {
+                       index: CaseFolding::Turkish,
+                       other: CaseFolding::Unicode,
+                   })));
+        // Queries that don't declare a mode are not checked
+        assert!(t.try_run_query(Query::new("İSTANBUL")).is_ok());
+        // Nor can the indexing pipelines change their mode
+        assert_eq!(t.set_case_folding(CaseFolding::Ascii),
+                   Err(CaseFoldingMismatch {
+                       index: CaseFolding::Turkish,
+                       other: CaseFolding::Ascii,
+                   }));
+        assert_eq!(t.case_folding(), Some(CaseFolding::Turkish));
+    }
+
+    #[test]
+    #[should_panic(expected = "Query folds case with Ascii, but the index with Unicode")]
+    fn case_folding_mismatch() {
+        use language::CaseFolding;
+
+        let mut t = create_and_fill_index("doc_index/case_folding_mismatch");
+        t.set_case_folding(CaseFolding::Unicode).unwrap();
+        t.run_query(Query::new("birds").case_folding(CaseFolding::Ascii));
+    }
+
+    #[test]
+    fn scored_query() {
+        let mut t = create_and_fill_index("doc_index/scored_query");
+        t.add_document(&[(Cow::from("text"), Cow::from("ceres"))]);
+        t.add_document(&[(Cow::from("text"),
+                          Cow::from("ceres orbits the sun between mars and jupiter"))]);
+        t.add_document(&[(Cow::from("text"), Cow::from("dwarf planet ceres"))]);
+        t.commit().unwrap();
+        let results = t.run_scored_query(Query::new("ceres"), 10, Bm25::default());
+        // Shorter documents first
+        assert_eq!(results.iter().map(|&(ref p, _)| p.clone()).collect::<Vec<_>>(),
+                   vec![Posting(DocId(3)), Posting(DocId(5)), Posting(DocId(4))]);
+        assert!(results[0].1 > results[1].1 && results[1].1 > results[2].1);
+        assert_eq!(t.run_scored_query(Query::new("ceres"), 2, Bm25::default()).len(), 2);
+        assert!(t.run_scored_query(Query::new("vesta"), 10, Bm25::default()).is_empty());
     }
 
     #[test]
@@ -182,8 +731,284 @@ mod tests {
         t.add_document(&[(Cow::from("text"), Cow::from("125 10"))]);
         t.add_document(&[(Cow::from("text"), Cow::from("10"))]);
         t.add_document(&[(Cow::from("text"), Cow::from("10"))]);
-        t.commit();
+        t.commit().unwrap();
         assert_eq!(t.frequent_terms_number().map(|(df, t, _)| (df, *t)).collect::<Vec<_>>(),
                    vec![(5, 10), (3, 125), (1, 2567)]);
     }
+
+    #[test]
+    fn dedupe_by_external_id() {
+        use std::collections::HashMap;
+        use document_index::QueryResultIterator;
+        use language::{CanApply, PipelineBucket};
+        use query::{DedupePolicy, Funnel, ToOperands, WeightingOptions};
+        use test_utils::create_fields;
+
+        let mut fields = create_fields("doc_index/dedupe_by_external_id", &["body"]);
+        // "ceres" is indexed twice. The newer version matches the query worse
+        let docs = [("ceres", "dwarf planet"),
+                    ("vesta", "planet"),
+                    ("ceres", "dwarf"),
+                    ("pallas", "asteroid")];
+        let ext_ids = docs.iter()
+            .enumerate()
+            .map(|(doc_id, &(ext_id, _))| (DocId(doc_id as u32), ext_id.to_string()))
+            .collect::<Vec<_>>();
+        for (doc_id, &(_, text)) in docs.iter().enumerate() {
+            for term in text.split_whitespace() {
+                fields.get_mut("body").unwrap().put(DocId(doc_id as u32), term.to_string());
+            }
+        }
+        fields.commit();
+
+        let query = |policy| {
+            let mut funnel = Funnel::create(&fields);
+            funnel.apply("dwarf".to_string());
+            funnel.apply("planet".to_string());
+            QueryResultIterator::with_options(funnel.to_operands(),
+                                              vec![],
+                                              WeightingOptions::exhaustive(),
+                                              &ext_ids)
+                .dedupe_by_external_id(policy)
+        };
+        let collect = |mut results: QueryResultIterator<String>| {
+            let mut collected = Vec::new();
+            while let Some((DocId(doc_id), ext_id)) = results.next_with_doc_id() {
+                collected.push((doc_id, ext_id));
+            }
+            (collected, results.suppressed())
+        };
+
+        let (all, suppressed) = collect(query(None));
+        assert_eq!(all.len(), 3);
+        assert_eq!(suppressed, 0);
+
+        let (highest, suppressed) = collect(query(Some(DedupePolicy::HighestRanked)));
+        assert_eq!(highest[0], (0, "ceres".to_string()));
+        assert_eq!(highest.len(), 2);
+        assert_eq!(suppressed, 1);
+
+        let (mut newest, suppressed) = collect(query(Some(DedupePolicy::Newest)));
+        newest.sort();
+        assert_eq!(newest, vec![(1, "vesta".to_string()), (2, "ceres".to_string())]);
+        assert_eq!(suppressed, 1);
+
+        // The newest DocIds can be given, e.g. from the derived index's lookup
+        let newest_doc_ids = ext_ids.iter()
+            .map(|&(doc_id, ref ext_id)| (ext_id.clone(), doc_id))
+            .filter(|&(_, doc_id)| doc_id != DocId(2))
+            .collect::<HashMap<_, _>>();
+        let given = query(None)
+            .newest_doc_ids(&newest_doc_ids)
+            .dedupe_by_external_id(Some(DedupePolicy::Newest));
+        let (mut newest, _) = collect(given);
+        newest.sort();
+        assert_eq!(newest, vec![(0, "ceres".to_string()), (1, "vesta".to_string())]);
+
+        // Pages never contain the same external id twice
+        let deduped = query(Some(DedupePolicy::HighestRanked)).collect::<Vec<_>>();
+        let first_page = query(Some(DedupePolicy::HighestRanked)).take(1).collect::<Vec<_>>();
+        let second_page = query(Some(DedupePolicy::HighestRanked)).skip(1).take(1).collect::<Vec<_>>();
+        assert_eq!(first_page.into_iter().chain(second_page).collect::<Vec<_>>(), deduped);
+        assert_eq!(deduped, vec!["ceres".to_string(), "vesta".to_string()]);
+    }
+
+    #[test]
+    fn query_builder() {
+        use language::CanApply;
+        use query::{clause_operand, Funnel, QueryBuilder, ToOperands, WeightingOperator,
+                    WeightingOptions};
+
+        let t = create_and_fill_index("doc_index/query_builder");
+        let run = |query| t.run_query(query).collect::<Vec<_>>();
+        let postings = |ids: &[u32]| ids.iter().map(|id| Posting(DocId(*id))).collect::<Vec<_>>();
+
+        // Clause terms are given as indexed, i.e. stemmed
+        assert_eq!(run(QueryBuilder::new("").must("text", "flew").must_not("text", "bird").build()),
+                   postings(&[2]));
+        assert_eq!(run(QueryBuilder::new("").must("number", "125").build()), postings(&[1]));
+        assert_eq!(run(QueryBuilder::new("birds").filter("number", "10").build()),
+                   postings(&[0]));
+        assert_eq!(run(QueryBuilder::new("").must("text", "comet").build()), postings(&[]));
+        assert_eq!(run(QueryBuilder::new("").must("color", "red").build()), postings(&[]));
+        assert_eq!(run(QueryBuilder::new("").must("number", "ten").build()), postings(&[]));
+        assert_eq!(run(QueryBuilder::new("").must_any("text", &["ocean", "unicorn"]).build()),
+                   postings(&[1, 2]));
+        // Parsed queries run the same clauses
+        let parsed = |input| run(QueryBuilder::parse("text", input).unwrap().build());
+        assert_eq!(parsed("flew NOT bird"), postings(&[2]));
+        assert_eq!(parsed("flew AND (ocean OR unicorn)"), postings(&[1, 2]));
+        assert_eq!(parsed("flew AND (comet OR mars)"), postings(&[]));
+        assert_eq!(parsed("NOT (ocean OR unicorn) flew"), postings(&[0]));
+
+        // Same as the equivalent hand-built operands
+        let mut funnel = Funnel::create(&t.documents.text);
+        funnel.apply("unicorn".to_string());
+        let ops = funnel.to_operands();
+        let q = Query::new("");
+        let filters = vec![clause_operand(&t.documents.text, &"flew".to_string(), &q).unwrap()];
+        let may = clause_operand(&t.documents.number, &10, &q).unwrap();
+        let excluded = vec![clause_operand(&t.documents.title, &"unicorn".to_string(), &q)
+                                .unwrap()];
+        let hand_built = WeightingOperator::create_with_options(ops.into_iter()
+                                                                    .chain(Some(may))
+                                                                    .collect(),
+                                                                filters,
+                                                                WeightingOptions::default())
+            .exclude(excluded)
+            .collect::<Vec<_>>();
+        let built = QueryBuilder::new("unicorns")
+            .must("text", "flew")
+            .may("number", "10")
+            .must_not("title", "unicorn")
+            .build();
+        assert_eq!(run(built), hand_built);
+        assert_eq!(hand_built, postings(&[0]));
+
+        // Counts, weights and scores see the clauses as well
+        let built = || QueryBuilder::new("").must("text", "flew").must_not("text", "bird").build();
+        assert_eq!(t.approx_count(built(), 10).estimate.round(), 1.);
+        assert_eq!(t.run_scored_query(QueryBuilder::new("flew").must_not("text", "bird").build(),
+                                      10,
+                                      Bm25::default())
+                       .len(),
+                   1);
+        let weights = t.query_term_weights(&QueryBuilder::new("").may("text", "ocean").build());
+        assert_eq!(weights.iter().map(|&(ref term, _, _)| term.as_str()).collect::<Vec<_>>(),
+                   vec!["ocean"]);
+    }
+
+    #[test]
+    fn lifecycle_logging() {
+        use log::Level;
+        use test_utils::capture_logs;
+
+        let records = capture_logs(|| {
+            let t = create_and_fill_index("doc_index/lifecycle_logging");
+            t.run_query(Query::new("birds")).count();
+        });
+        let has = |target: &str, level: Level, message: &str| {
+            records.iter()
+                .any(|&(ref t, l, ref m)| t == target && l == level && m.starts_with(message))
+        };
+        assert!(has("perlin::storage", Level::Debug, "Loaded 0 metadata entries"));
+        assert!(has("perlin::index", Level::Debug, "Committed field text: 3 documents"));
+        assert!(has("perlin::storage", Level::Debug, "Persisted 0 metadata entries"));
+        // Once per query, not per posting
+        assert_eq!(records.iter().filter(|&&(ref t, _, _)| t == "perlin::query").count(), 1);
+    }
+
+    #[test]
+    fn stale_metadata() {
+        let mut t = create_and_fill_index("doc_index/stale_metadata");
+        t.metadata.set("offset", &[3]).unwrap();
+        t.commit().unwrap();
+        // A new index in the same place starts without documents and metadata
+        let path = test_dir().join("doc_index/stale_metadata");
+        let mut t = TestIndex::create(path.clone()).unwrap();
+        assert_eq!(t.metadata.get("offset"), None);
+        t.commit().unwrap();
+        assert!(TestIndex::create(path).unwrap().metadata.keys().is_empty());
+    }
+
+    #[test]
+    fn autocomplete() {
+        use language::EdgeNGramFilter;
+
+        let mut t = create_and_fill_index("doc_index/autocomplete");
+        t.set_title_pipeline(pipeline!(title
+                                       WhitespaceTokenizer
+                                       > LowercaseFilter
+                                       > EdgeNGramFilter(2, 10)));
+        t.set_query_pipeline(query_pipeline!(
+            WhitespaceTokenizer
+                > LowercaseFilter
+                > [All in title]));
+        t.add_document(&[(Cow::from("title"), Cow::from("Searching Ceres"))]);
+        t.add_document(&[(Cow::from("title"), Cow::from("Seasons on Vesta"))]);
+        t.commit().unwrap();
+        should_yield(&t, "sea", &[3, 4]);
+        should_yield(&t, "sear", &[3]);
+        should_yield(&t, "Searching", &[3]);
+        should_yield(&t, "sea ves", &[4]);
+    }
+
+    mod external_ids {
+        use std::borrow::Cow;
+
+        use perlin_core::index::posting::DocId;
+        use field::Field;
+        use language::{LowercaseFilter, WhitespaceTokenizer};
+        use query::{DedupePolicy, Query};
+        use validation::ValidationOutcome;
+        use test_utils::create_test_dir;
+
+        #[derive(PerlinDocument)]
+        #[ExternalId(String)]
+        pub struct ExtTest {
+            text: Field<String>,
+        }
+
+        #[test]
+        fn lookups_both_ways() {
+            let mut t = ExtTestIndex::create(create_test_dir("doc_index/external_id_lookups"))
+                .unwrap();
+            t.set_text_pipeline(pipeline!(text WhitespaceTokenizer > LowercaseFilter));
+            t.set_query_pipeline(query_pipeline!(WhitespaceTokenizer
+                                                 > LowercaseFilter
+                                                 > [All in text]));
+            let doc = |text| vec![(Cow::from("text"), Cow::from(text))];
+            t.add_document("ceres".to_string(), &doc("dwarf planet"));
+            t.add_documents(vec![("vesta".to_string(), doc("asteroid")),
+                                 ("pallas".to_string(), doc("asteroid"))])
+                .unwrap();
+            assert_eq!(t.doc_id_for_external(&"vesta".to_string()), Some(DocId(1)));
+            assert_eq!(t.external_id_for_doc(DocId(2)), Some(&"pallas".to_string()));
+            assert_eq!(t.doc_id_for_external(&"hygiea".to_string()), None);
+            assert_eq!(t.external_id_for_doc(DocId(3)), None);
+
+            // Indexing an external id again supersedes the older document
+            assert_eq!(t.add_document("ceres".to_string(), &doc("dwarf")),
+                       ValidationOutcome::Accept);
+            t.commit().unwrap();
+            assert_eq!(t.doc_id_for_external(&"ceres".to_string()), Some(DocId(3)));
+            assert_eq!(t.external_id_for_doc(DocId(0)), Some(&"ceres".to_string()));
+            // Both versions stay indexed, dedupe hides the older one
+            assert_eq!(t.run_query(Query::new("dwarf")).collect::<Vec<_>>(),
+                       vec!["ceres".to_string(), "ceres".to_string()]);
+            let newest = Query::new("dwarf")
+                .dedupe_by_external_id(true)
+                .dedupe_policy(DedupePolicy::Newest);
+            let mut results = t.run_query(newest);
+            assert_eq!(results.next_with_doc_id(), Some((DocId(3), "ceres".to_string())));
+            assert_eq!(results.next(), None);
+            assert_eq!(t.run_query_first_k(Query::new("dwarf").dedupe_by_external_id(true), 2),
+                       vec!["ceres".to_string()]);
+            assert_eq!(t.run_query(Query::new("asteroid")).count(), 2);
+        }
+
+        #[test]
+        fn warnings_with_external_id() {
+            use std::sync::{Arc, Mutex};
+            use validation;
+
+            let mut t = ExtTestIndex::create(create_test_dir("doc_index/external_id_warnings"))
+                .unwrap();
+            t.set_text_pipeline(pipeline!(text WhitespaceTokenizer > LowercaseFilter));
+            t.set_validator(validation::warn_only(validation::max_field_length("text", 5)));
+            let warnings = Arc::new(Mutex::new(Vec::new()));
+            let sink = warnings.clone();
+            t.set_warning_sink(Box::new(move |doc_id, external_id, warnings| {
+                sink.lock().unwrap().push((doc_id, external_id.cloned(), warnings.len()))
+            }));
+            let doc = |text| vec![(Cow::from("text"), Cow::from(text))];
+            t.add_document("ceres".to_string(), &doc("dwarf planet"));
+            t.add_documents(vec![("vesta".to_string(), doc("rock")),
+                                 ("pallas".to_string(), doc("asteroid"))])
+                .unwrap();
+            assert_eq!(*warnings.lock().unwrap(),
+                       vec![(DocId(0), Some("ceres".to_string()), 1),
+                            (DocId(2), Some("pallas".to_string()), 1)]);
+        }
+    }
 }
@@ -0,0 +1,293 @@
+use std::hash::Hash;
+use std::collections::{BTreeSet, HashMap};
+
+use perlin_core::index::posting::{Posting, PostingIterator, DocId};
+
+use field::Field;
+
+/// Options for `diff_fields`
+#[derive(Debug, Copy, Clone)]
+pub struct DiffOptions {
+    /// Number of added and removed DocIds kept per term
+    /// Counts are always exact.
+    pub max_examples: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions { max_examples: 10 }
+    }
+}
+
+/// Posting list differences of a term contained in both fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct TermDiff<T> {
+    pub term: T,
+    /// DocIds only in b. At most `DiffOptions::max_examples`
+    pub added: Vec<DocId>,
+    /// DocIds only in a. At most `DiffOptions::max_examples`
+    pub removed: Vec<DocId>,
+    pub added_count: usize,
+    pub removed_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDiff<T> {
+    pub only_in_a: Vec<T>,
+    pub only_in_b: Vec<T>,
+    /// Terms in both fields whose posting lists differ
+    pub changed: Vec<TermDiff<T>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub terms_only_in_a: usize,
+    pub terms_only_in_b: usize,
+    pub changed_terms: usize,
+    pub postings_added: usize,
+    pub postings_removed: usize,
+}
+
+impl<T> IndexDiff<T> {
+    pub fn summary(&self) -> DiffSummary {
+        DiffSummary {
+            terms_only_in_a: self.only_in_a.len(),
+            terms_only_in_b: self.only_in_b.len(),
+            changed_terms: self.changed.len(),
+            postings_added: self.changed.iter().map(|d| d.added_count).sum(),
+            postings_removed: self.changed.iter().map(|d| d.removed_count).sum(),
+        }
+    }
+}
+
+/// Compares two fields, e.g. the same corpus indexed with two different
+/// pipelines.
+///
+/// Vocabularies are aligned by term, not by `TermId`.
+/// DocIds are compared as they are. So both fields need to contain the same
+/// documents under the same DocIds for posting differences to be meaningful.
+/// If they do not, use `diff_fields_by_external_id`.
+///
+/// All terms are reported in ascending order.
+pub fn diff_fields<T>(a: &Field<T>, b: &Field<T>, options: DiffOptions) -> IndexDiff<T>
+    where T: Hash + Eq + Ord + Clone
+{
+    diff(a, b, options, |postings_a, postings_b| (postings_a, postings_b))
+}
+
+/// Like `diff_fields`, but aligns documents by their external ids instead of
+/// their DocIds. `ext_ids_a` and `ext_ids_b` are sorted by DocId.
+///
+/// Only documents with an external id in both fields are compared.
+/// All DocIds in the result are DocIds of `a`.
+pub fn diff_fields_by_external_id<T, E>(a: &Field<T>,
+                                        ext_ids_a: &[(DocId, E)],
+                                        b: &Field<T>,
+                                        ext_ids_b: &[(DocId, E)],
+                                        options: DiffOptions)
+                                        -> IndexDiff<T>
+    where T: Hash + Eq + Ord + Clone,
+          E: Hash + Eq
+{
+    let by_ext_id = ext_ids_a.iter()
+        .map(|&(doc_id, ref ext_id)| (ext_id, doc_id))
+        .collect::<HashMap<_, _>>();
+    // Sorted by the DocId of b, as ext_ids_b is
+    let b_to_a = ext_ids_b.iter()
+        .filter_map(|&(doc_id, ref ext_id)| by_ext_id.get(ext_id).map(|&a_id| (doc_id, a_id)))
+        .collect::<Vec<_>>();
+    let mut in_both = b_to_a.iter().map(|&(_, a_id)| a_id).collect::<Vec<_>>();
+    in_both.sort();
+
+    diff(a, b, options, |postings_a, postings_b| {
+        let postings_a = postings_a.into_iter()
+            .filter(|doc_id| in_both.binary_search(doc_id).is_ok())
+            .collect();
+        let mut postings_b = postings_b.into_iter()
+            .filter_map(|doc_id| {
+                b_to_a.binary_search_by_key(&doc_id, |&(b_id, _)| b_id)
+                    .ok()
+                    .map(|i| b_to_a[i].1)
+            })
+            .collect::<Vec<_>>();
+        postings_b.sort();
+        (postings_a, postings_b)
+    })
+}
+
+// `align` maps the posting lists of a term into one DocId space.
+// Both results need to be sorted
+fn diff<T, F>(a: &Field<T>, b: &Field<T>, options: DiffOptions, align: F) -> IndexDiff<T>
+    where T: Hash + Eq + Ord + Clone,
+          F: Fn(Vec<DocId>, Vec<DocId>) -> (Vec<DocId>, Vec<DocId>)
+{
+    let terms_a = a.iterate_terms().map(|(t, _)| t.clone()).collect::<BTreeSet<_>>();
+    let terms_b = b.iterate_terms().map(|(t, _)| t.clone()).collect::<BTreeSet<_>>();
+
+    let mut changed = Vec::new();
+    for term in terms_a.intersection(&terms_b) {
+        let (postings_a, postings_b) = align(doc_ids(a, term), doc_ids(b, term));
+        if postings_a == postings_b {
+            continue;
+        }
+        let mut diff = TermDiff {
+            term: term.clone(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            added_count: 0,
+            removed_count: 0,
+        };
+        let (mut i, mut j) = (0, 0);
+        while i < postings_a.len() || j < postings_b.len() {
+            if j == postings_b.len() || (i < postings_a.len() && postings_a[i] < postings_b[j]) {
+                diff.removed_count += 1;
+                if diff.removed.len() < options.max_examples {
+                    diff.removed.push(postings_a[i]);
+                }
+                i += 1;
+            } else if i == postings_a.len() || postings_b[j] < postings_a[i] {
+                diff.added_count += 1;
+                if diff.added.len() < options.max_examples {
+                    diff.added.push(postings_b[j]);
+                }
+                j += 1;
+            } else {
+                i += 1;
+                j += 1;
+            }
+        }
+        changed.push(diff);
+    }
+
+    IndexDiff {
+        only_in_a: terms_a.difference(&terms_b).cloned().collect(),
+        only_in_b: terms_b.difference(&terms_a).cloned().collect(),
+        changed,
+    }
+}
+
+fn doc_ids<T: Hash + Eq + Ord>(field: &Field<T>, term: &T) -> Vec<DocId> {
+    match field.query_atom(term) {
+        (_, PostingIterator::Decoder(decoder)) => decoder.map(|Posting(doc_id)| doc_id).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::DocId;
+    use rust_stemmers::Algorithm;
+
+    use field::{Field, Fields, FieldSupplement};
+    use language::{CanApply, Funnel, PipelineBucket, Stemmer, WhitespaceTokenizer};
+    use test_utils::create_test_dir;
+    use super::*;
+
+    fn create_fields(name: &str) -> Fields<String> {
+        let path = create_test_dir(name);
+        let mut fields = Fields::new();
+        fields.add_field("raw".to_string(), &path, FieldSupplement::None).unwrap();
+        fields.add_field("stemmed".to_string(), &path, FieldSupplement::None).unwrap();
+        let docs = ["birds flew", "bird flies", "the bird"];
+        for (i, doc) in docs.iter().enumerate() {
+            let doc_id = DocId(i as u32);
+            {
+                let raw = fields.get_mut("raw").unwrap();
+                for token in doc.split_whitespace() {
+                    raw.put(doc_id, token.to_string());
+                }
+            }
+            let stemmed = fields.get_mut("stemmed").unwrap();
+            WhitespaceTokenizer::create(Stemmer::create(Algorithm::English,
+                                                        Funnel::create(doc_id, stemmed)))
+                .apply(*doc);
+        }
+        fields.commit();
+        fields
+    }
+
+    #[test]
+    fn stemmer_diff() {
+        let fields = create_fields("diff/stemmer_diff");
+        let diff = diff_fields(fields.get("raw").unwrap(),
+                               fields.get("stemmed").unwrap(),
+                               DiffOptions::default());
+        assert_eq!(diff.only_in_a, vec!["birds".to_string(), "flies".to_string()]);
+        assert_eq!(diff.only_in_b, vec!["fli".to_string()]);
+        assert_eq!(diff.changed,
+                   vec![TermDiff {
+                            term: "bird".to_string(),
+                            added: vec![DocId(0)],
+                            removed: vec![],
+                            added_count: 1,
+                            removed_count: 0,
+                        }]);
+        assert_eq!(diff.summary(),
+                   DiffSummary {
+                       terms_only_in_a: 2,
+                       terms_only_in_b: 1,
+                       changed_terms: 1,
+                       postings_added: 1,
+                       postings_removed: 0,
+                   });
+        // And the other way around
+        let reversed = diff_fields(fields.get("stemmed").unwrap(),
+                                   fields.get("raw").unwrap(),
+                                   DiffOptions::default());
+        assert_eq!(reversed.changed[0].removed, vec![DocId(0)]);
+    }
+
+    fn put_docs<'a>(field: &mut Field<String>,
+                    docs: &[(&'a str, &str)])
+                    -> Vec<(DocId, &'a str)> {
+        let mut ext_ids = Vec::new();
+        for (i, &(ext_id, content)) in docs.iter().enumerate() {
+            let doc_id = DocId(i as u32);
+            for token in content.split_whitespace() {
+                field.put(doc_id, token.to_string());
+            }
+            ext_ids.push((doc_id, ext_id));
+        }
+        ext_ids
+    }
+
+    #[test]
+    fn external_id_alignment() {
+        let path = create_test_dir("diff/external_id_alignment");
+        let mut fields = Fields::new();
+        fields.add_field("a".to_string(), &path, FieldSupplement::None).unwrap();
+        fields.add_field("b".to_string(), &path, FieldSupplement::None).unwrap();
+        // b contains the documents of a in reverse order, plus "d" which a lacks.
+        // Document "z" gained "bird"
+        let ext_ids_a = put_docs(fields.get_mut("a").unwrap(),
+                                 &[("x", "bird"), ("y", "bird fish"), ("z", "fish")]);
+        let ext_ids_b = put_docs(fields.get_mut("b").unwrap(),
+                                 &[("d", "bird"), ("z", "fish bird"), ("y", "bird fish"), ("x", "bird")]);
+        fields.commit();
+        let (a, b) = (fields.get("a").unwrap(), fields.get("b").unwrap());
+
+        // By DocId the shifted documents show up as changes
+        assert_eq!(diff_fields(a, b, DiffOptions::default()).changed[0].added,
+                   vec![DocId(2), DocId(3)]);
+        let diff = diff_fields_by_external_id(a, &ext_ids_a, b, &ext_ids_b, DiffOptions::default());
+        assert_eq!(diff.changed,
+                   vec![TermDiff {
+                            term: "bird".to_string(),
+                            added: vec![DocId(2)],
+                            removed: vec![],
+                            added_count: 1,
+                            removed_count: 0,
+                        }]);
+    }
+
+    #[test]
+    fn example_limit() {
+        let fields = create_fields("diff/example_limit");
+        let diff = diff_fields(fields.get("raw").unwrap(),
+                               fields.get("stemmed").unwrap(),
+                               DiffOptions { max_examples: 0 });
+        assert!(diff.changed[0].added.is_empty());
+        assert_eq!(diff.changed[0].added_count, 1);
+        assert_eq!(diff.summary().postings_added, 1);
+    }
+}
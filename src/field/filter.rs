@@ -1,10 +1,20 @@
 use std::hash::Hash;
+use std::mem::size_of;
 
 use perlin_core::index::Index;
 use perlin_core::index::vocabulary::TermId;
 
+use memory::{MemoryReport, MemoryUsage};
+use query::Operand;
+use field::any_of;
 
-pub struct Filter<T>(Vec<(usize, T, TermId)>);
+
+pub struct Filter<T> {
+    // Sorted by document frequency, descending
+    by_df: Vec<(usize, T, TermId)>,
+    // Sorted by term, for range queries
+    by_term: Vec<(T, TermId)>,
+}
 
 impl<T: Hash + Eq + Ord + Clone + 'static> Filter<T> {
     pub fn commit(&mut self, index: &Index<T>) {
@@ -13,15 +23,54 @@ impl<T: Hash + Eq + Ord + Clone + 'static> Filter<T> {
             .collect::<Vec<_>>();
 
         sorted_terms.sort_by(|a, b| a.0.cmp(&b.0).reverse());
-        self.0 = sorted_terms;
-
+        self.by_term = sorted_terms.iter().map(|&(_, ref t, term_id)| (t.clone(), term_id)).collect();
+        self.by_term.sort_by(|a, b| a.0.cmp(&b.0));
+        self.by_df = sorted_terms;
     }
 
     pub fn frequent_terms<'a>(&'a self) -> Box<Iterator<Item = (usize, &T, TermId)> + 'a> {
-        Box::new(self.0.iter().map(move |&(ref df, ref t, ref term_id)| (*df, t, *term_id)))
+        Box::new(self.by_df.iter().map(move |&(ref df, ref t, ref term_id)| (*df, t, *term_id)))
+    }
+
+    /// Returns the union of the postings of all terms in `from..=to`.
+    /// `None` leaves that side of the range open.
+    /// An empty range yields nothing.
+    ///
+    /// Only terms known at the last commit are considered.
+    pub fn range<'a>(&self,
+                     index: &'a Index<T>,
+                     field: &str,
+                     label: String,
+                     from: Option<&T>,
+                     to: Option<&T>)
+                     -> Operand<'a> {
+        let start = match from {
+            Some(from) => {
+                match self.by_term.binary_search_by(|&(ref t, _)| t.cmp(from)) {
+                    Ok(index) | Err(index) => index,
+                }
+            }
+            None => 0,
+        };
+        let terms = self.by_term[start..]
+            .iter()
+            .map(|&(ref t, _)| t)
+            .take_while(|t| to.map(|to| *t <= to).unwrap_or(true));
+        any_of(index, terms, field, label)
     }
 
     pub fn new() -> Self {
-        Filter(vec![])
+        Filter {
+            by_df: vec![],
+            by_term: vec![],
+        }
+    }
+}
+
+impl<T> MemoryUsage for Filter<T> {
+    fn memory_report(&self) -> MemoryReport {
+        MemoryReport::leaf("filter",
+                           self.by_df.capacity() * size_of::<(usize, T, TermId)>() +
+                           self.by_term.capacity() * size_of::<(T, TermId)>())
     }
 }
@@ -1,7 +1,16 @@
 use std::hash::Hash;
+use std::mem::size_of;
 use std::collections::HashMap;
 
+use perlin_core::index::Index;
 
+use memory::{MemoryReport, MemoryUsage};
+use query::Operand;
+use field::any_of;
+
+
+/// Elements have to be added parent first and only once.
+/// So the hierarchy is a forest and cannot contain cycles.
 pub struct Hierarchy<T>(HashMap<T, Vec<T>>, Vec<T>);
 
 impl<T: Hash + Eq + Clone> Hierarchy<T> {
@@ -14,17 +23,19 @@ impl<T: Hash + Eq + Clone> Hierarchy<T> {
             panic!("Hierarchy element already exists!");
         }
 
-        self.0.insert(term.clone(), vec![]);
-
+        // Look up the parent before inserting `term`,
+        // so an element cannot become its own parent
         if let Some(parent) = parent {
             if let Some(parent_node) = self.0.get_mut(&parent) {
-                parent_node.push(term);
+                parent_node.push(term.clone());
             } else {
                 panic!("Added hierarchical elements in wrong order!");
             }
         } else {
             self.1.push(term.clone());
         }
+
+        self.0.insert(term, vec![]);
     }
 
     pub fn get_child_terms(&self, term: &T) -> Option<&[T]> {
@@ -39,4 +50,90 @@ impl<T: Hash + Eq + Clone> Hierarchy<T> {
     pub fn get_root_terms(&self) -> &[T] {
         &self.1
     }
+
+    /// Returns `node` and all elements below it, parents first.
+    /// Empty if `node` is unknown.
+    pub fn descendants<'a>(&'a self, node: &'a T) -> Vec<&'a T> {
+        let mut result = Vec::new();
+        if !self.0.contains_key(node) {
+            return result;
+        }
+        let mut stack = vec![node];
+        while let Some(term) = stack.pop() {
+            result.push(term);
+            if let Some(children) = self.get_child_terms(term) {
+                stack.extend(children.iter().rev());
+            }
+        }
+        result
+    }
+}
+
+impl<T: Hash + Eq + Clone + 'static> Hierarchy<T> {
+    /// Returns the union of the postings of `node` and all its descendants.
+    /// Yields nothing if `node` is unknown.
+    pub fn descendants_filter<'a>(&self,
+                                  index: &'a Index<T>,
+                                  field: &str,
+                                  label: String,
+                                  node: &T)
+                                  -> Operand<'a> {
+        any_of(index, self.descendants(node).into_iter(), field, label)
+    }
+}
+
+impl<T: Hash + Eq> MemoryUsage for Hierarchy<T> {
+    fn memory_report(&self) -> MemoryReport {
+        let nodes = self.0.capacity() * size_of::<(T, Vec<T>)>() +
+                    self.0.values().map(|children| children.capacity() * size_of::<T>()).sum::<usize>();
+        MemoryReport::node("hierarchy",
+                           vec![MemoryReport::leaf("nodes", nodes),
+                                MemoryReport::leaf("roots", self.1.capacity() * size_of::<T>())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memory::MemoryUsage;
+    use super::Hierarchy;
+
+    fn hierarchy(size: usize) -> Hierarchy<usize> {
+        let mut hierarchy = Hierarchy::new();
+        for i in 0..size {
+            hierarchy.add_element(i, if i < 10 { None } else { Some(i % 10) });
+        }
+        hierarchy
+    }
+
+    #[test]
+    fn descendants() {
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.add_element("electronics", None);
+        hierarchy.add_element("phones", Some("electronics"));
+        hierarchy.add_element("android", Some("phones"));
+        hierarchy.add_element("tv", Some("electronics"));
+        hierarchy.add_element("garden", None);
+        assert_eq!(hierarchy.descendants(&"electronics"),
+                   vec![&"electronics", &"phones", &"android", &"tv"]);
+        assert_eq!(hierarchy.descendants(&"android"), vec![&"android"]);
+        assert!(hierarchy.descendants(&"cars").is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong order")]
+    fn own_parent() {
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.add_element("electronics", Some("electronics"));
+    }
+
+    #[test]
+    fn memory_report() {
+        let small = hierarchy(1000).memory_report();
+        let large = hierarchy(2000).memory_report();
+        assert_eq!(small.bytes, small.children.iter().map(|c| c.bytes).sum());
+        let ratio = large.bytes as f64 / small.bytes as f64;
+        assert!(ratio > 1.5 && ratio < 3., format!("ratio {}", ratio));
+        assert_eq!(small.children.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+                   vec!["nodes", "roots"]);
+    }
 }
@@ -2,17 +2,29 @@ use std::hash::Hash;
 use std::path::Path;
 use std::ops::{Deref, DerefMut};
 use std::collections::HashMap;
+use std::iter::Rev;
+use std::vec::IntoIter;
+use std::time::Instant;
+use std::fmt::Debug;
+use std::mem::size_of;
 
 use perlin_core::index::Index;
-use perlin_core::index::posting::DocId;
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+use perlin_core::index::posting::{Posting, PostingIterator, PostingDecoder, DocId};
+use perlin_core::index::vocabulary::{SharedVocabulary, TermId, Vocabulary};
 
-use language::PipelineBucket;
+use language::{PipelineBucket, SkipReason};
+use query::{Operand, MinShouldMatch, Weight};
+use utils::BloomFilter;
+use memory::{MemoryReport, MemoryUsage};
 
 mod filter;
 mod hierarchy;
+mod diff;
 
 pub use field::filter::Filter;
 pub use field::hierarchy::Hierarchy;
+pub use field::diff::{diff_fields, diff_fields_by_external_id, DiffOptions, DiffSummary, IndexDiff, TermDiff};
 
 pub enum FieldSupplement<T> {
     None,
@@ -22,25 +34,446 @@ pub enum FieldSupplement<T> {
 
 pub struct Field<T: Hash + Eq> {
     index: Index<T>,
-    pub name: String,
+    // Same vocabulary the index uses, for lookups by term
+    vocabulary: SharedVocabulary<T>,
+    // Number of distinct terms. Updated on commit
+    term_count: usize,
+    // Read only, `Fields` looks fields up by it
+    name: String,
     pub term_doc_ratio: f32,
     pub supplement: FieldSupplement<T>,
+    /// If set, only queries granted this capability can search the field
+    pub required_capability: Option<String>,
+    bloom_filter: Option<(BloomFilter, f64)>,
+    // Number of terms put per document. Sorted by DocId
+    doc_lengths: Vec<(DocId, u32)>,
+    total_length: usize,
+    // Sorted vocabulary for prefix queries if enabled. Rebuilt on commit
+    sorted_terms: Option<Vec<T>>,
+    // Terms put since `start_batch`. Indexed sorted by term on commit
+    batch: Option<Vec<(T, DocId)>>,
+    // Number of tokens dropped as repeats per document. Sorted by DocId
+    collapsed: Vec<(DocId, u32)>,
+}
+
+impl<T: Hash + Eq> Field<T> {
+    /// Returns the name the field was added with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of documents that put at least one term into this field
+    pub fn doc_count(&self) -> usize {
+        self.doc_lengths.len()
+    }
+
+    /// Returns the number of terms that were put into this field for `doc_id`
+    pub fn doc_length(&self, doc_id: DocId) -> Option<usize> {
+        self.doc_lengths
+            .binary_search_by_key(&doc_id, |&(d_id, _)| d_id)
+            .ok()
+            .map(|index| self.doc_lengths[index].1 as usize)
+    }
+
+    pub fn avg_doc_length(&self) -> f32 {
+        if self.doc_lengths.is_empty() {
+            0.
+        } else {
+            self.total_length as f32 / self.doc_lengths.len() as f32
+        }
+    }
+
+    /// Returns the length of `doc_id` divided by the average document length
+    pub fn relative_doc_length(&self, doc_id: DocId) -> Option<f32> {
+        self.doc_length(doc_id).map(|length| length as f32 / self.avg_doc_length())
+    }
+
+    pub fn is_visible_to(&self, capabilities: &[String]) -> bool {
+        if let Some(ref required) = self.required_capability {
+            capabilities.contains(required)
+        } else {
+            true
+        }
+    }
+
+    /// Returns false if `term` is definitely not in this field's vocabulary.
+    /// Always true if no bloom filter is enabled.
+    pub fn may_contain(&self, term: &T) -> bool {
+        if let Some((ref bloom_filter, _)) = self.bloom_filter {
+            bloom_filter.may_contain(term)
+        } else {
+            true
+        }
+    }
+
+    pub fn bloom_filter(&self) -> Option<&BloomFilter> {
+        self.bloom_filter.as_ref().map(|&(ref bloom_filter, _)| bloom_filter)
+    }
+
+    /// Returns the number of tokens of `doc_id` a `RepeatCollapser` dropped
+    /// before they reached this field
+    pub fn collapsed_tokens(&self, doc_id: DocId) -> u32 {
+        match self.collapsed.binary_search_by_key(&doc_id, |&(d_id, _)| d_id) {
+            Ok(index) => self.collapsed[index].1,
+            Err(_) => 0,
+        }
+    }
+}
+
+impl<T: Hash + Eq + Ord> Field<T> {
+    /// Returns the idf and the postings of `term`, `None` if it has none in
+    /// this field. Terms the bloom filter rules out skip the vocabulary lookup.
+    pub fn query_term<'a>(&'a self, term: &T) -> Option<(f32, PostingDecoder<'a>)> {
+        if !self.may_contain(term) {
+            return None;
+        }
+        count_event!("{}: vocabulary lookup", self.name);
+        match self.index.query_atom(term) {
+            (idf, PostingIterator::Decoder(decoder)) => Some((idf.0, decoder)),
+            _ => None,
+        }
+    }
 }
 
 impl<T: Hash + Eq + Ord + Clone + 'static> Field<T> {
+    /// Buffers the terms put into this field until the next `commit`, which
+    /// indexes them grouped by term. Like perlin_core's `index_collection`
+    /// this touches every posting list once per batch instead of once per
+    /// document.
+    pub fn start_batch(&mut self) {
+        if self.batch.is_none() {
+            self.batch = Some(Vec::new());
+        }
+    }
+
     pub fn commit(&mut self) {
+        if let Some(mut batch) = self.batch.take() {
+            // Stable, so the DocIds of a term stay ascending
+            batch.sort_by(|a, b| a.0.cmp(&b.0));
+            for (term, doc_id) in batch {
+                count_event!("{}: index term", self.name);
+                self.index.index_term(doc_id, term);
+            }
+        }
+        // Indexing terms and committing is where the index allocates pages
+        count_event!("{}: commit", self.name);
         self.index.commit();
+        self.term_count = self.index.iterate_terms().count();
         if let FieldSupplement::Filter(ref mut filter) = self.supplement {
             filter.commit(&self.index);
         }
+        // Put terms are already in the bloom filter. It only needs to grow
+        // once the vocabulary outgrows it
+        let term_count = self.term_count;
+        let outgrown = match self.bloom_filter {
+            Some((ref filter, rate)) if filter.capacity() < term_count => Some(rate),
+            _ => None,
+        };
+        if let Some(false_positive_rate) = outgrown {
+            self.rebuild_bloom_filter(false_positive_rate);
+        }
+        if self.sorted_terms.is_some() {
+            self.rebuild_sorted_terms();
+        }
+        debug!(target: "perlin::index",
+               "Committed field {}: {} documents",
+               self.name,
+               self.doc_count());
+    }
+
+    /// Keeps a sorted copy of the vocabulary to answer prefix queries in
+    /// O(log n + matches). It is rebuilt on every commit.
+    pub fn enable_prefix_queries(&mut self) {
+        self.rebuild_sorted_terms();
+    }
+
+    fn rebuild_sorted_terms(&mut self) {
+        let mut terms = self.index
+            .iterate_terms()
+            .map(|(term, _)| term.clone())
+            .collect::<Vec<_>>();
+        terms.sort();
+        self.sorted_terms = Some(terms);
+    }
+
+    /// Puts a bloom filter over the vocabulary in front of query lookups
+    /// (see `query_term`). Queries for terms that are definitely not
+    /// contained then skip the vocabulary lookup.
+    ///
+    /// Terms are inserted as they are put. Once a commit finds the vocabulary
+    /// larger than the filter was sized for, the filter is rebuilt for twice
+    /// the vocabulary, so commits rehash the vocabulary only rarely.
+    pub fn enable_bloom_filter(&mut self, false_positive_rate: f64) {
+        self.rebuild_bloom_filter(false_positive_rate);
+    }
+
+    /// Returns the `TermId` of `term` if it is in the vocabulary.
+    /// With a shared vocabulary, that includes terms of other fields.
+    pub fn term_id(&self, term: &T) -> Option<TermId> {
+        self.vocabulary.get(term)
+    }
+
+    /// Returns the number of documents containing `term` without decoding its postings
+    pub fn doc_frequency(&self, term: &T) -> usize {
+        // With a shared vocabulary the term may only have postings in other fields
+        match (self.term_id(term), self.index.query_atom(term)) {
+            (Some(term_id), (_, PostingIterator::Decoder(_))) => self.index.term_df(&term_id),
+            _ => 0,
+        }
+    }
+
+    /// Iterates over the terms of this field's vocabulary, e.g. for
+    /// autocompletion. The order is unspecified.
+    /// Use `enable_prefix_queries` for sorted prefix lookups.
+    pub fn terms<'a>(&'a self) -> Box<Iterator<Item = &'a T> + 'a> {
+        Box::new(self.index.iterate_terms().map(|(term, _)| term))
+    }
+
+    /// Returns the number of distinct terms in this field as of the last commit
+    pub fn term_count(&self) -> usize {
+        self.term_count
+    }
+
+    fn rebuild_bloom_filter(&mut self, false_positive_rate: f64) {
+        count_event!("{}: bloom filter rebuild", self.name);
+        let mut bloom_filter = BloomFilter::new(2 * self.index.iterate_terms().count(),
+                                                false_positive_rate);
+        for (term, _) in self.index.iterate_terms() {
+            bloom_filter.insert(term);
+        }
+        self.bloom_filter = Some((bloom_filter, false_positive_rate));
+    }
+
+    /// Returns the postings of `term` in descending doc id order
+    ///
+    /// Postings are stored ascending and delta encoded. So this decodes and
+    /// buffers the whole posting list before yielding anything.
+    pub fn reverse_postings(&self, term: &T) -> Rev<IntoIter<Posting>> {
+        let postings = match self.index.query_atom(term) {
+            (_, PostingIterator::Decoder(decoder)) => decoder.collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        postings.into_iter().rev()
+    }
+}
+
+impl<T: Hash + Eq + Ord + Clone + Debug + 'static> Field<T> {
+    /// Returns an operand over all documents with a term in `from..=to`,
+    /// e.g. for `Query::filter_by_operand`. `None` leaves that side open.
+    ///
+    /// Requires a `FieldSupplement::Filter`, which is kept up to date by `commit`.
+    /// Also returns `None` if the field is not visible to `capabilities`.
+    pub fn range<'a>(&'a self,
+                     from: Option<&T>,
+                     to: Option<&T>,
+                     capabilities: &[String])
+                     -> Option<Operand<'a>> {
+        if !self.is_visible_to(capabilities) {
+            return None;
+        }
+        match self.supplement {
+            FieldSupplement::Filter(ref filter) => {
+                let label = format!("{}..={}", bound_label(from), bound_label(to));
+                Some(filter.range(&self.index, &self.name, label, from, to))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns an operand over all documents tagged with `node` or any of
+    /// its descendants. Requires a `FieldSupplement::Hierarchy`.
+    /// Also returns `None` if the field is not visible to `capabilities`.
+    pub fn descendants<'a>(&'a self, node: &T, capabilities: &[String]) -> Option<Operand<'a>> {
+        if !self.is_visible_to(capabilities) {
+            return None;
+        }
+        match self.supplement {
+            FieldSupplement::Hierarchy(ref hierarchy) => {
+                let label = format!("{:?}/*", node);
+                Some(hierarchy.descendants_filter(&self.index, &self.name, label, node))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Renders one side of a range, empty if it is open
+fn bound_label<T: Debug>(bound: Option<&T>) -> String {
+    bound.map(|bound| format!("{:?}", bound)).unwrap_or_default()
+}
+
+/// Unions the postings of `terms` into a single operand
+fn any_of<'a, 'b, T, I>(index: &'a Index<T>, terms: I, field: &str, label: String) -> Operand<'a>
+    where T: Hash + Eq + 'b,
+          I: Iterator<Item = &'b T>
+{
+    let mut operands = Vec::new();
+    for term in terms {
+        if let (_, PostingIterator::Decoder(decoder)) = index.query_atom(term) {
+            operands.push(PeekableSeekable::new(Operand::Term(Weight(1.0),
+                                                              decoder,
+                                                              label.clone(),
+                                                              field.to_string())));
+        }
+    }
+    Operand::Any(Weight(1.0),
+                 MinShouldMatch::create(operands, 1),
+                 label,
+                 field.to_string())
+}
+
+impl Field<String> {
+    /// Returns an operand over all documents with a term starting with
+    /// `prefix`, or `None` if the field is not visible to `capabilities`.
+    ///
+    /// Without `enable_prefix_queries` the whole vocabulary is scanned.
+    pub fn query_prefix<'a>(&'a self,
+                            prefix: &str,
+                            capabilities: &[String])
+                            -> Option<Operand<'a>> {
+        if !self.is_visible_to(capabilities) {
+            return None;
+        }
+        let label = format!("{}*", prefix);
+        Some(if let Some(ref sorted_terms) = self.sorted_terms {
+            let start = match sorted_terms.binary_search_by(|term| term.as_str().cmp(prefix)) {
+                Ok(index) | Err(index) => index,
+            };
+            let terms = sorted_terms[start..].iter().take_while(|term| term.starts_with(prefix));
+            any_of(&self.index, terms, &self.name, label)
+        } else {
+            let terms = self.index
+                .iterate_terms()
+                .map(|(term, _)| term)
+                .filter(|term| term.starts_with(prefix));
+            any_of(&self.index, terms, &self.name, label)
+        })
+    }
+
+    /// Returns up to `limit` terms within `max_distance` edits of `term`
+    /// together with their document frequencies, e.g. for "did you mean".
+    /// Closest terms come first, ties are broken by document frequency.
+    ///
+    /// Distances are Levenshtein distances over chars. `term` itself is
+    /// returned with distance 0 if it is contained.
+    pub fn suggest(&self, term: &str, max_distance: u32, limit: usize) -> Vec<(String, usize)> {
+        let max_distance = max_distance as usize;
+        let query = term.chars().collect::<Vec<_>>();
+        let mut candidate = Vec::new();
+        let mut found = Vec::new();
+        for (t, term_id) in self.index.iterate_terms() {
+            candidate.clear();
+            candidate.extend(t.chars());
+            if let Some(distance) = levenshtein(&query, &candidate, max_distance) {
+                found.push((distance, self.index.term_df(term_id), t));
+            }
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(a.2.cmp(b.2)));
+        found.into_iter().take(limit).map(|(_, df, t)| (t.clone(), df)).collect()
+    }
+}
+
+/// Levenshtein distance between `a` and `b` if it is at most `max`
+fn levenshtein(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    let len_diff = if a.len() > b.len() { a.len() - b.len() } else { b.len() - a.len() };
+    if len_diff > max {
+        return None;
+    }
+    let mut previous = (0..b.len() + 1).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        // Distances never decrease from row to row
+        if current.iter().all(|&d| d > max) {
+            return None;
+        }
+        ::std::mem::swap(&mut previous, &mut current);
+    }
+    let distance = previous[b.len()];
+    if distance <= max { Some(distance) } else { None }
+}
+
+impl<T: Hash + Eq> MemoryUsage for Field<T> {
+    fn memory_report(&self) -> MemoryReport {
+        let mut children =
+            vec![MemoryReport::leaf("doc_lengths",
+                                    self.doc_lengths.capacity() * size_of::<(DocId, u32)>())];
+        if !self.collapsed.is_empty() {
+            children.push(MemoryReport::leaf("collapsed",
+                                             self.collapsed.capacity() *
+                                             size_of::<(DocId, u32)>()));
+        }
+        if let Some(ref batch) = self.batch {
+            children.push(MemoryReport::leaf("batch", batch.capacity() * size_of::<(T, DocId)>()));
+        }
+        match self.supplement {
+            FieldSupplement::Filter(ref filter) => children.push(filter.memory_report()),
+            FieldSupplement::Hierarchy(ref hierarchy) => children.push(hierarchy.memory_report()),
+            FieldSupplement::None => {}
+        }
+        if let Some((ref bloom_filter, _)) = self.bloom_filter {
+            children.push(bloom_filter.memory_report());
+        }
+        if let Some(ref sorted_terms) = self.sorted_terms {
+            children.push(MemoryReport::leaf("sorted_terms",
+                                             sorted_terms.capacity() * size_of::<T>()));
+        }
+        MemoryReport::node(&self.name, children)
+    }
+}
+
+impl<T: Hash + Eq> MemoryUsage for Fields<T> {
+    fn memory_report(&self) -> MemoryReport {
+        MemoryReport::node("fields",
+                           self.fields.iter().map(|field| field.memory_report()).collect())
     }
 }
 
 impl<TTerm> PipelineBucket<TTerm> for Field<TTerm>
-    where TTerm: Hash + Eq + Ord
+    where TTerm: Hash + Eq + Ord + Clone
 {
     fn put(&mut self, doc_id: DocId, term: TTerm) {
-        self.index.index_term(doc_id, term);
+        count_event!("put term");
+        if let Some((ref mut bloom_filter, _)) = self.bloom_filter {
+            bloom_filter.insert(&term);
+        }
+        let new_document = match self.doc_lengths.last() {
+            Some(&(last_doc_id, _)) => last_doc_id != doc_id,
+            None => true,
+        };
+        if new_document {
+            self.doc_lengths.push((doc_id, 0));
+        }
+        if let Some(&mut (_, ref mut length)) = self.doc_lengths.last_mut() {
+            *length += 1;
+        }
+        self.total_length += 1;
+        if let Some(ref mut batch) = self.batch {
+            batch.push((term, doc_id));
+        } else {
+            count_event!("{}: index term", self.name);
+            self.index.index_term(doc_id, term);
+        }
+    }
+
+    /// Counts tokens dropped as repeats, see `collapsed_tokens`
+    fn skip(&mut self, doc_id: DocId, count: u32, reason: SkipReason) {
+        if reason == SkipReason::Repeat {
+            let new_document = match self.collapsed.last() {
+                Some(&(last_doc_id, _)) => last_doc_id != doc_id,
+                None => true,
+            };
+            if new_document {
+                self.collapsed.push((doc_id, 0));
+            }
+            if let Some(&mut (_, ref mut collapsed)) = self.collapsed.last_mut() {
+                *collapsed += count;
+            }
+        }
     }
 }
 
@@ -60,15 +493,83 @@ impl<T: Hash + Eq> Deref for Field<T> {
 }
 
 
+/// A set of fields sharing the same term type.
+///
+/// Fields are kept in insertion order. `commit` and query operand construction
+/// iterate them in that order, so two identical builds allocate their pages
+/// and construct their operands identically.
+///
+/// Fields are looked up by name with `get` and `get_mut` and iterated in
+/// order with `iter`.
+///
+/// Created with `new_shared` all fields share one vocabulary. A term then has
+/// the same `TermId` in every field.
 pub struct Fields<T: Hash + Eq> {
-    pub fields: HashMap<String, Field<T>>,
+    fields: Vec<Field<T>>,
+    names: HashMap<String, usize>,
+    vocabulary: Option<SharedVocabulary<T>>,
+}
+
+impl<T: Hash + Eq> Fields<T> {
+    /// Returns the field called `name` if it exists
+    pub fn get(&self, name: &str) -> Option<&Field<T>> {
+        self.names.get(name).map(|i| &self.fields[*i])
+    }
+
+    /// Returns the field called `name` mutably if it exists
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Field<T>> {
+        if let Some(i) = self.names.get(name) {
+            Some(&mut self.fields[*i])
+        } else {
+            None
+        }
+    }
+
+    /// Iterates over all fields in the order they were added
+    pub fn iter(&self) -> ::std::slice::Iter<Field<T>> {
+        self.fields.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn shares_vocabulary(&self) -> bool {
+        self.vocabulary.is_some()
+    }
 }
 
 impl<T: Hash + Eq + Ord + Clone + 'static> Fields<T> {
+    /// Returns the number of documents containing `term` summed over all fields
+    pub fn global_df(&self, term: &T) -> usize {
+        self.fields
+            .iter()
+            .map(|field| field.doc_frequency(term))
+            .sum()
+    }
+
+    /// Starts a batch on every field. See `Field::start_batch`
+    pub fn start_batch(&mut self) {
+        for field in &mut self.fields {
+            field.start_batch();
+        }
+    }
+
+    /// Commits all fields in the order they were added
     pub fn commit(&mut self) {
-        for field in self.fields.values_mut() {
-            (field as &mut Field<T>).commit();
+        let start = Instant::now();
+        for field in &mut self.fields {
+            field.commit();
         }
+        let elapsed = start.elapsed();
+        info!(target: "perlin::index",
+              "Committed {} fields in {} ms",
+              self.fields.len(),
+              elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000));
     }
 
     pub fn add_field(&mut self,
@@ -77,37 +578,335 @@ impl<T: Hash + Eq + Ord + Clone + 'static> Fields<T> {
                      supplement: FieldSupplement<T>)
                      -> Result<(), ()> {
         use perlin_core::page_manager::{RamPageCache, FsPageManager};
-        use perlin_core::index::vocabulary::SharedVocabulary;
-        use perlin_core::index::Index;
-        if self.fields.contains_key(&name) {
+        if self.names.contains_key(&name) {
             return Err(());
         } else {
-            let page_cache =
-                RamPageCache::new(FsPageManager::new(&path.join(format!("{}_page_cache", name))));
-            self.fields.insert(name.clone(),
-                               Field {
-                                   name,
-                                   index: Index::new(page_cache, SharedVocabulary::new()),
-                                   term_doc_ratio: 1.0,
-                                   supplement,
-                               });
+            let page_cache_path = path.join(format!("{}_page_cache", name));
+            debug!(target: "perlin::storage",
+                   "Creating page cache for field {} at {:?}",
+                   name,
+                   page_cache_path);
+            let page_cache = RamPageCache::new(FsPageManager::new(&page_cache_path));
+            let vocabulary = if let Some(ref vocabulary) = self.vocabulary {
+                vocabulary.clone()
+            } else {
+                SharedVocabulary::new()
+            };
+            self.names.insert(name.clone(), self.fields.len());
+            self.fields.push(Field {
+                                 name,
+                                 index: Index::new(page_cache, vocabulary.clone()),
+                                 vocabulary,
+                                 term_count: 0,
+                                 term_doc_ratio: 1.0,
+                                 supplement,
+                                 required_capability: None,
+                                 bloom_filter: None,
+                                 doc_lengths: Vec::new(),
+                                 total_length: 0,
+                                 sorted_terms: None,
+                                 batch: None,
+                                 collapsed: Vec::new(),
+                             });
             return Ok(());
         }
     }
 
     pub fn new() -> Self {
-        Fields { fields: HashMap::new() }
+        Fields {
+            fields: Vec::new(),
+            names: HashMap::new(),
+            vocabulary: None,
+        }
+    }
+
+    /// Creates fields that all share one vocabulary
+    pub fn new_shared() -> Self {
+        Fields {
+            fields: Vec::new(),
+            names: HashMap::new(),
+            vocabulary: Some(SharedVocabulary::new()),
+        }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use perlin_core::index::posting::DocId;
-    use field::{Fields};
+    use perlin_core::index::posting::{PostingIterator, DocId};
+    use field::{Fields, FieldSupplement};
 
     use rust_stemmers::Algorithm;
 
+    use language::{CanApply, PipelineBucket};
+    use query::{Funnel, Operand, ToOperands};
+    use memory::MemoryUsage;
+    use test_utils::{create_fields, create_test_dir, test_dir};
+
+    #[test]
+    fn by_name_access() {
+        let mut fields = create_fields("fields/by_name_access", &["title", "body"]);
+        assert!(fields.add_field("title".to_string(),
+                                 &test_dir().join("fields/by_name_access"),
+                                 FieldSupplement::None)
+            .is_err());
+        fields.get_mut("body").unwrap().put(DocId(0), "deimos".to_string());
+        fields.commit();
+        assert_eq!(fields.get("title").unwrap().name(), "title");
+        assert_eq!(fields.get("body").unwrap().name(), "body");
+        assert!(fields.get("tags").is_none());
+        assert_eq!(fields.len(), 2);
+    }
+
+    #[test]
+    fn shared_vocabulary() {
+        let path = create_test_dir("fields/shared_vocabulary");
+        let mut fields = Fields::new_shared();
+        fields.add_field("title".to_string(), &path, FieldSupplement::None).unwrap();
+        fields.add_field("body".to_string(), &path, FieldSupplement::None).unwrap();
+        fields.get_mut("title").unwrap().put(DocId(0), "phobos".to_string());
+        fields.get_mut("title").unwrap().put(DocId(1), "deimos".to_string());
+        fields.get_mut("body").unwrap().put(DocId(0), "deimos".to_string());
+        fields.get_mut("body").unwrap().put(DocId(1), "deimos".to_string());
+        fields.commit();
+
+        let term_id = |field: &str, term: &str| {
+            fields.get(field)
+                .unwrap()
+                .iterate_terms()
+                .find(|&(t, _)| t == term)
+                .map(|(_, term_id)| *term_id)
+        };
+        assert!(fields.shares_vocabulary());
+        assert_eq!(term_id("title", "deimos"), term_id("body", "deimos"));
+        assert_eq!(fields.global_df(&"deimos".to_string()), 3);
+        assert_eq!(fields.global_df(&"phobos".to_string()), 1);
+        assert_eq!(fields.global_df(&"ceres".to_string()), 0);
+    }
+
+    #[test]
+    fn bloom_filter() {
+        use query::{clause_operand, Query};
+        use test_utils::events;
+
+        let mut fields = create_fields("fields/bloom_filter", &["body", "title"]);
+        for doc_id in 0..1000 {
+            fields.get_mut("body").unwrap().put(DocId(doc_id), format!("term{}", doc_id % 370));
+            fields.get_mut("title").unwrap().put(DocId(doc_id), format!("term{}", doc_id % 370));
+        }
+        fields.commit();
+        fields.get_mut("body").unwrap().enable_bloom_filter(0.01);
+        // Terms put after enabling are inserted right away
+        fields.get_mut("body").unwrap().put(DocId(1000), "ceres".to_string());
+        {
+            let body = fields.get("body").unwrap();
+            assert!(body.iterate_terms().all(|(term, _)| body.may_contain(term)));
+            assert!(body.may_contain(&"ceres".to_string()));
+            assert!(body.bloom_filter().unwrap().memory_usage() > 0);
+
+            // The false positive rate holds for the field
+            let false_positives = (0..10_000)
+                .filter(|i| body.may_contain(&format!("absent{}", i)))
+                .count();
+            assert!(false_positives < 300, format!("{} false positives", false_positives));
+
+            // A miss skips the vocabulary lookup, without a bloom filter it does not
+            let miss = (0..)
+                .map(|i| format!("absent{}", i))
+                .find(|term| !body.may_contain(term))
+                .unwrap();
+            let title = fields.get("title").unwrap();
+            let lookups = |f: &Fn()| {
+                events(|| f()).iter().filter(|event| event.ends_with(": vocabulary lookup")).count()
+            };
+            assert_eq!(lookups(&|| assert!(body.query_term(&miss).is_none())), 0);
+            assert_eq!(lookups(&|| assert!(title.query_term(&miss).is_none())), 1);
+            assert_eq!(lookups(&|| assert!(body.query_term(&"term3".to_string()).is_some())),
+                       1);
+            let query = Query::new("");
+            assert_eq!(lookups(&|| assert!(clause_operand(body, &miss, &query).is_none())), 0);
+            assert_eq!(lookups(&|| {
+                           Query::new("").filter_by(body, &miss);
+                       }),
+                       0);
+            assert_eq!(lookups(&|| {
+                           let mut funnel = Funnel::create(&fields);
+                           funnel.apply(miss.clone());
+                           assert_eq!(funnel.to_operands().len(), 0);
+                       }),
+                       1);
+        }
+
+        // Commits don't rebuild the filter until the vocabulary outgrows it
+        let capacity = fields.get("body").unwrap().bloom_filter().unwrap().capacity();
+        assert_eq!(capacity, 740);
+        let rebuilds = |events: Vec<String>| {
+            events.iter().filter(|event| *event == "body: bloom filter rebuild").count()
+        };
+        assert_eq!(rebuilds(events(|| fields.commit())), 0);
+        let grown = events(|| {
+            for i in 0..1000 {
+                fields.get_mut("body").unwrap().put(DocId(1001 + i), format!("new{}", i));
+            }
+            fields.commit();
+        });
+        assert_eq!(rebuilds(grown), 1);
+        let body = fields.get("body").unwrap();
+        assert!(body.bloom_filter().unwrap().capacity() > capacity);
+        assert!(body.iterate_terms().all(|(term, _)| body.may_contain(term)));
+    }
+
+    #[test]
+    fn statistics() {
+        let mut fields = create_fields("fields/statistics", &["body", "title"]);
+        for doc_id in 0..10 {
+            let body = fields.get_mut("body").unwrap();
+            body.put(DocId(doc_id), "phobos".to_string());
+            if doc_id % 2 == 0 {
+                body.put(DocId(doc_id), "deimos".to_string());
+            }
+        }
+        fields.commit();
+        let body = fields.get("body").unwrap();
+        assert_eq!(body.doc_count(), 10);
+        assert_eq!(body.doc_length(DocId(2)), Some(2));
+        assert_eq!(body.doc_length(DocId(3)), Some(1));
+        assert_eq!(body.doc_length(DocId(10)), None);
+        assert_eq!(body.avg_doc_length(), 1.5);
+        assert_eq!(body.term_count(), 2);
+        let mut terms = body.terms().cloned().collect::<Vec<_>>();
+        terms.sort();
+        assert_eq!(terms, vec!["deimos".to_string(), "phobos".to_string()]);
+        assert_eq!(body.doc_frequency(&"phobos".to_string()), 10);
+        assert_eq!(body.doc_frequency(&"deimos".to_string()), 5);
+        assert_eq!(body.doc_frequency(&"ceres".to_string()), 0);
+        assert_eq!(fields.get("title").unwrap().doc_count(), 0);
+    }
+
+    #[test]
+    fn memory_report() {
+        let mut fields = create_fields("fields/memory_report", &["body", "title"]);
+        for doc_id in 0..100 {
+            fields.get_mut("body").unwrap().put(DocId(doc_id), "phobos".to_string());
+        }
+        fields.commit();
+        let report = fields.memory_report();
+        assert_eq!(report.children.len(), 2);
+        assert!(report.children[0].bytes > 0);
+        assert_eq!(report.children[1].bytes, 0);
+        assert_eq!(report.bytes, report.children[0].bytes);
+
+        fields.get_mut("title").unwrap().start_batch();
+        fields.get_mut("title").unwrap().put(DocId(0), "deimos".to_string());
+        let title = fields.get("title").unwrap().memory_report();
+        assert!(title.children.iter().any(|child| child.name == "batch" && child.bytes > 0));
+    }
+
+    #[test]
+    fn reverse_postings() {
+        let mut fields = create_fields("fields/reverse_postings", &["body"]);
+        for doc_id in 0..100 {
+            let field = fields.get_mut("body").unwrap();
+            field.put(DocId(doc_id), "phobos".to_string());
+            if doc_id % 3 == 0 {
+                field.put(DocId(doc_id), "deimos".to_string());
+            }
+        }
+        fields.commit();
+        let field = fields.get("body").unwrap();
+        let forward = match field.query_atom(&"deimos".to_string()) {
+            (_, PostingIterator::Decoder(decoder)) => decoder.collect::<Vec<_>>(),
+            _ => vec![],
+        };
+        let mut reversed = field.reverse_postings(&"deimos".to_string()).collect::<Vec<_>>();
+        assert_eq!(forward.len(), 34);
+        reversed.reverse();
+        assert_eq!(reversed, forward);
+        assert_eq!(field.reverse_postings(&"ceres".to_string()).count(), 0);
+    }
+
+    #[test]
+    fn declaration_order() {
+        let names = ["zeta", "alpha", "mu", "beta"];
+        let mut fields = create_fields("fields/declaration_order", &names);
+        for name in &names {
+            let field = fields.get_mut(name).unwrap();
+            field.put(DocId(0), "deimos".to_string());
+            field.put(DocId(1), "phobos".to_string());
+        }
+        fields.commit();
+        assert_eq!(fields.iter().map(|f| f.name()).collect::<Vec<_>>(),
+                   names.to_vec());
+
+        let mut funnel = Funnel::create(&fields);
+        funnel.apply("phobos".to_string());
+        let operand_fields = funnel.to_operands()
+            .into_iter()
+            .map(|op| op.inner().field().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(operand_fields, names.to_vec());
+    }
+
+    #[test]
+    fn quiet_posting_decode() {
+        use test_utils::capture_logs;
+
+        let mut fields = create_fields("fields/quiet_posting_decode", &["body"]);
+        for doc_id in 0..1000 {
+            let body = fields.get_mut("body").unwrap();
+            body.put(DocId(doc_id), format!("term{}", doc_id % 10));
+            body.put(DocId(doc_id), "common".to_string());
+        }
+        fields.commit();
+
+        // Decoding postings is the hot path of every query. Nothing may log there
+        let body = fields.get("body").unwrap();
+        let mut decoded = 0;
+        let logs = capture_logs(|| for i in 0..10 {
+            for term in &[format!("term{}", i), "common".to_string()] {
+                if let (_, PostingIterator::Decoder(decoder)) = body.query_atom(term) {
+                    decoded += decoder.count();
+                }
+            }
+        });
+        assert_eq!(decoded, 10 * (100 + 1000));
+        assert!(logs.is_empty(), "{:?}", logs);
+    }
+
+    #[test]
+    fn deterministic_builds() {
+        use test_utils::events;
+
+        let names = ["zeta", "alpha", "mu", "beta"];
+        let build = |dir: &str| {
+            let mut fields = create_fields(dir, &names);
+            events(|| {
+                fields.get_mut("mu").unwrap().start_batch();
+                for doc_id in 0..500 {
+                    for (i, name) in names.iter().enumerate() {
+                        let term = format!("term{}", (doc_id as usize * (i + 1)) % 97);
+                        fields.get_mut(name).unwrap().put(DocId(doc_id), term);
+                    }
+                }
+                fields.commit();
+            })
+        };
+        // Every call that lets the index allocate pages, in order
+        let first = build("fields/deterministic_builds_first");
+        let second = build("fields/deterministic_builds_second");
+        assert_eq!(first, second);
+        // Fields commit in declaration order
+        let commits = first.iter()
+            .filter(|event| event.ends_with(": commit"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(commits,
+                   names.iter().map(|name| format!("{}: commit", name)).collect::<Vec<_>>());
+        // Batched terms are indexed right before their field commits
+        let mu_commit = first.iter().position(|event| event == "mu: commit").unwrap();
+        assert!(first[mu_commit - 500..mu_commit].iter().all(|event| event == "mu: index term"));
+    }
+
     #[derive(PerlinDocument)]
     pub struct FilterTest {
         body: Fields<String>,
@@ -157,7 +956,7 @@ mod tests {
     //     impl FilterTest {
     //         pub fn create(path: &Path) -> Self {
     //             use perlin_core::page_manager::{RamPageCache, FsPageManager};
-    //             use perlin_core::index::vocabulary::SharedVocabulary;
+    //             use perlin_core::index::vocabulary::{SharedVocabulary, TermId, Vocabulary};
     //             use perlin_core::index::Index;
     //             let body_page_cache =
     //                 RamPageCache::new(
@@ -179,4 +978,152 @@ mod tests {
     // }
 
 
+
+    #[test]
+    fn prefix_query() {
+        let mut fields = create_fields("fields/prefix_query", &["body", "scan"]);
+        let docs = ["computer science", "computing", "compass", "commuter", "computer"];
+        for (doc_id, doc) in docs.iter().enumerate() {
+            for term in doc.split_whitespace() {
+                fields.get_mut("body").unwrap().put(DocId(doc_id as u32), term.to_string());
+                fields.get_mut("scan").unwrap().put(DocId(doc_id as u32), term.to_string());
+            }
+        }
+        fields.get_mut("body").unwrap().enable_prefix_queries();
+        fields.commit();
+        for field in &["body", "scan"] {
+            let field = fields.get(field).unwrap();
+            let ids = |prefix| {
+                field.query_prefix(prefix, &[]).unwrap().map(|p| p.0).collect::<Vec<_>>()
+            };
+            assert_eq!(ids("comput"), vec![DocId(0), DocId(1), DocId(4)]);
+            assert_eq!(ids("com"), (0..5).map(DocId).collect::<Vec<_>>());
+            assert_eq!(ids("computer"), vec![DocId(0), DocId(4)]);
+            assert_eq!(ids("sci"), vec![DocId(0)]);
+            assert!(ids("x").is_empty());
+        }
+
+        let has_sorted_terms = |fields: &Fields<String>, name| {
+            fields.get(name)
+                .unwrap()
+                .memory_report()
+                .children
+                .iter()
+                .any(|child| child.name == "sorted_terms" && child.bytes > 0)
+        };
+        assert!(has_sorted_terms(&fields, "body"));
+        assert!(!has_sorted_terms(&fields, "scan"));
+        fields.get_mut("body").unwrap().required_capability = Some("search".to_string());
+        assert!(fields.get("body").unwrap().query_prefix("com", &[]).is_none());
+    }
+
+    #[test]
+    fn range_filter() {
+        use field::Filter;
+
+        let path = create_test_dir("fields/range_filter");
+        let mut fields = Fields::new();
+        fields.add_field("price".to_string(), &path, FieldSupplement::Filter(Filter::new()))
+            .unwrap();
+        fields.add_field("body".to_string(), &path, FieldSupplement::None).unwrap();
+        for (doc_id, price) in [5u64, 20, 35, 50, 80].iter().enumerate() {
+            fields.get_mut("price").unwrap().put(DocId(doc_id as u32), *price);
+        }
+        fields.commit();
+
+        let ids = |fields: &Fields<u64>, from: Option<u64>, to: Option<u64>| {
+            fields.get("price")
+                .unwrap()
+                .range(from.as_ref(), to.as_ref(), &[])
+                .unwrap()
+                .map(|posting| posting.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&fields, Some(20), Some(50)), vec![DocId(1), DocId(2), DocId(3)]);
+        assert_eq!(ids(&fields, Some(21), Some(49)), vec![DocId(2)]);
+        assert_eq!(ids(&fields, None, Some(20)), vec![DocId(0), DocId(1)]);
+        assert_eq!(ids(&fields, Some(50), None), vec![DocId(3), DocId(4)]);
+        assert!(ids(&fields, Some(50), Some(20)).is_empty());
+        assert!(ids(&fields, Some(90), None).is_empty());
+        assert!(fields.get("body").unwrap().range(None, None, &[]).is_none());
+
+        let label = |fields: &Fields<u64>, from: Option<u64>, to: Option<u64>| {
+            match fields.get("price").unwrap().range(from.as_ref(), to.as_ref(), &[]) {
+                Some(Operand::Any(_, _, label, _)) => label,
+                _ => panic!("expected an Any operand"),
+            }
+        };
+        assert_eq!(label(&fields, Some(20), Some(50)), "20..=50");
+        assert_eq!(label(&fields, None, Some(50)), "..=50");
+        assert_eq!(label(&fields, Some(20), None), "20..=");
+
+        fields.get_mut("price").unwrap().required_capability = Some("pricing".to_string());
+        assert!(fields.get("price").unwrap().range(Some(&20), None, &[]).is_none());
+        assert!(fields.get("price")
+            .unwrap()
+            .range(Some(&20), None, &["pricing".to_string()])
+            .is_some());
+        fields.get_mut("price").unwrap().required_capability = None;
+
+        // New terms show up after the next commit
+        fields.get_mut("price").unwrap().put(DocId(5), 30);
+        fields.commit();
+        assert_eq!(ids(&fields, Some(20), Some(35)), vec![DocId(1), DocId(2), DocId(5)]);
+    }
+
+    #[test]
+    fn hierarchy_descendants() {
+        use field::Hierarchy;
+
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.add_element("electronics".to_string(), None);
+        hierarchy.add_element("phones".to_string(), Some("electronics".to_string()));
+        hierarchy.add_element("android".to_string(), Some("phones".to_string()));
+        hierarchy.add_element("garden".to_string(), None);
+        let path = create_test_dir("fields/hierarchy_descendants");
+        let mut fields = Fields::new();
+        fields.add_field("category".to_string(), &path, FieldSupplement::Hierarchy(hierarchy))
+            .unwrap();
+        let docs = ["android", "garden", "electronics", "phones", "android"];
+        for (doc_id, category) in docs.iter().enumerate() {
+            fields.get_mut("category").unwrap().put(DocId(doc_id as u32), category.to_string());
+        }
+        fields.get_mut("category").unwrap().required_capability = Some("catalog".to_string());
+        fields.commit();
+
+        let category = fields.get("category").unwrap();
+        assert!(category.descendants(&"phones".to_string(), &[]).is_none());
+        let ids = |node: &str| {
+            category.descendants(&node.to_string(), &["catalog".to_string()])
+                .unwrap()
+                .map(|posting| posting.0)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("electronics"), vec![DocId(0), DocId(2), DocId(3), DocId(4)]);
+        assert_eq!(ids("phones"), vec![DocId(0), DocId(3), DocId(4)]);
+        assert_eq!(ids("android"), vec![DocId(0), DocId(4)]);
+        assert!(ids("cars").is_empty());
+    }
+
+    #[test]
+    fn suggest() {
+        let mut fields = create_fields("fields/suggest", &["body"]);
+        let docs = ["deimos phobos", "deimos", "demos", "daimon", "phobia", "größe", "grosse"];
+        for (doc_id, doc) in docs.iter().enumerate() {
+            for term in doc.split_whitespace() {
+                fields.get_mut("body").unwrap().put(DocId(doc_id as u32), term.to_string());
+            }
+        }
+        fields.commit();
+        let body = fields.get("body").unwrap();
+        assert_eq!(body.suggest("deimso", 2, 10), vec![("deimos".to_string(), 2)]);
+        assert_eq!(body.suggest("deimos", 1, 10),
+                   vec![("deimos".to_string(), 2), ("demos".to_string(), 1)]);
+        assert_eq!(body.suggest("deimos", 1, 1), vec![("deimos".to_string(), 2)]);
+        assert_eq!(body.suggest("phobos", 2, 10),
+                   vec![("phobos".to_string(), 1), ("phobia".to_string(), 1)]);
+        // Over chars, not bytes
+        assert_eq!(body.suggest("grüße", 1, 10), vec![("größe".to_string(), 1)]);
+        assert!(body.suggest("ceres", 1, 10).is_empty());
+    }
 }
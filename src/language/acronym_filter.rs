@@ -0,0 +1,81 @@
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Lowercases its input like `LowercaseFilter`.
+/// Short all-caps tokens like "IT" or "NASA" are additionally passed on
+/// verbatim. So "IT" stays findable even if "it" is dropped by a case
+/// sensitive `StopwordFilter`.
+pub struct AcronymFilter<TCallback> {
+    max_len: usize,
+    callback: TCallback,
+}
+
+impl<TCallback> AcronymFilter<TCallback> {
+    /// Tokens of up to `max_len` characters are considered acronyms
+    pub fn create(max_len: usize, callback: TCallback) -> Self {
+        AcronymFilter {
+            max_len: max_len,
+            callback: callback,
+        }
+    }
+
+    fn is_acronym(&self, input: &str) -> bool {
+        let len = input.chars().count();
+        len > 1 && len <= self.max_len && input.chars().all(|c| c.is_uppercase())
+    }
+}
+
+impl<'a, TCallback> CanApply<&'a str> for AcronymFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: &str) {
+        self.callback.apply(input.to_lowercase());
+        if self.is_acronym(input) {
+            self.callback.apply(input.to_string());
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, TCallback> ToOperands<'a> for AcronymFilter<TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use language::{CanApply, StopwordFilter, WhitespaceTokenizer};
+    use test_utils::Collector;
+    use super::AcronymFilter;
+
+    #[test]
+    fn acronyms() {
+        let collector = Collector::new();
+        WhitespaceTokenizer::create(AcronymFilter::create(4, collector.clone()))
+            .apply("IT department NASA A TOOLONG Ünïcode ÄÖ");
+        assert_eq!(collector.terms(),
+                   vec!["it", "IT", "department", "nasa", "NASA", "a", "toolong", "ünïcode",
+                        "äö", "ÄÖ"]);
+    }
+
+    #[test]
+    fn stopwords() {
+        let collector = Collector::new();
+        let mut pipe =
+            AcronymFilter::create(4,
+                                  StopwordFilter::create(vec!["it".to_string()], collector.clone())
+                                      .case_sensitive(true));
+        pipe.apply("IT");
+        pipe.apply("it");
+        assert_eq!(collector.terms(), vec!["IT"]);
+    }
+}
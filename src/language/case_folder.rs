@@ -0,0 +1,154 @@
+use unicode_normalization::UnicodeNormalization;
+
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CaseFolding {
+    /// Unicode lowercasing plus folding of 'ß' to "ss" and final sigma to sigma.
+    /// Note that 'İ' becomes "i̇" (i with combining dot above) in this mode.
+    Unicode,
+    /// Like `Unicode` but with Turkish and Azeri special casing:
+    /// 'İ' -> 'i' and 'I' -> 'ı'
+    Turkish,
+    /// Only lowercases ASCII letters. Fastest, but leaves everything else untouched
+    Ascii,
+}
+
+impl CaseFolding {
+    /// Name of the mode as recorded in an index's metadata
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CaseFolding::Unicode => "unicode",
+            CaseFolding::Turkish => "turkish",
+            CaseFolding::Ascii => "ascii",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "unicode" => Some(CaseFolding::Unicode),
+            "turkish" => Some(CaseFolding::Turkish),
+            "ascii" => Some(CaseFolding::Ascii),
+            _ => None,
+        }
+    }
+}
+
+/// A query or new indexing pipelines fold case differently than the index
+/// was recorded with. Terms would silently stop matching.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CaseFoldingMismatch {
+    /// The mode recorded for the index
+    pub index: CaseFolding,
+    /// The mode of the query or of the new indexing pipelines
+    pub other: CaseFolding,
+}
+
+/// Normalizes `input` to NFC and folds its case according to `mode`.
+/// So composed and decomposed forms of the same text result in the same term.
+pub fn fold_case(input: &str, mode: CaseFolding) -> String {
+    match mode {
+        CaseFolding::Ascii => input.to_ascii_lowercase(),
+        CaseFolding::Unicode => fold_char_by_char(input, |c, out| out.extend(c.to_lowercase())),
+        CaseFolding::Turkish => {
+            fold_char_by_char(input, |c, out| match c {
+                'İ' => out.push('i'),
+                'I' => out.push('ı'),
+                _ => out.extend(c.to_lowercase()),
+            })
+        }
+    }
+}
+
+fn fold_char_by_char<F: Fn(char, &mut String)>(input: &str, lowercase: F) -> String {
+    let mut result = String::with_capacity(input.len());
+    for c in input.nfc() {
+        lowercase(c, &mut result);
+    }
+    if result.contains(|c| c == 'ß' || c == 'ς') {
+        result = result.replace('ß', "ss").replace('ς', "σ");
+    }
+    result
+}
+
+/// Locale aware replacement for `LowercaseFilter`.
+/// Use the same `CaseFolding` in the indexing and the query pipeline.
+/// Declaring the modes with `set_case_folding` on the derived index and
+/// `Query::case_folding` turns a mismatch into an error.
+pub struct CaseFolder<TCallback> {
+    mode: CaseFolding,
+    callback: TCallback,
+}
+
+impl<TCallback> CaseFolder<TCallback> {
+    pub fn create(mode: CaseFolding, callback: TCallback) -> Self {
+        CaseFolder {
+            mode: mode,
+            callback: callback,
+        }
+    }
+}
+
+impl<'a, TCallback> CanApply<&'a str> for CaseFolder<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: &str) {
+        self.callback.apply(fold_case(input, self.mode))
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, TCallback> ToOperands<'a> for CaseFolder<TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_case, CaseFolding};
+
+    #[test]
+    fn turkish() {
+        let fold = |s| fold_case(s, CaseFolding::Turkish);
+        assert_eq!(fold("İSTANBUL"), "istanbul");
+        assert_eq!(fold("ISPARTA"), "ısparta");
+        assert_eq!(fold("ısparta"), "ısparta");
+        assert_eq!(fold("istanbul"), "istanbul");
+        // Decomposed İ
+        assert_eq!(fold("I\u{307}STANBUL"), "istanbul");
+    }
+
+    #[test]
+    fn unicode() {
+        let fold = |s| fold_case(s, CaseFolding::Unicode);
+        assert_eq!(fold("ISPARTA"), "isparta");
+        assert_eq!(fold("İ"), "i\u{307}");
+        assert_eq!(fold("Straße"), fold("STRASSE"));
+        assert_eq!(fold("ΟΔΟΣ"), fold("οδος"));
+        // NFC and NFD input
+        assert_eq!(fold("Caf\u{e9}"), fold("Cafe\u{301}"));
+    }
+
+    #[test]
+    fn names() {
+        for &mode in &[CaseFolding::Unicode, CaseFolding::Turkish, CaseFolding::Ascii] {
+            assert_eq!(CaseFolding::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(CaseFolding::from_name("klingon"), None);
+    }
+
+    #[test]
+    fn ascii() {
+        assert_eq!(fold_case("CAFÉ", CaseFolding::Ascii), "cafÉ");
+    }
+}
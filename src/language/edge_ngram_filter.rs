@@ -0,0 +1,92 @@
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Emits the leading prefixes of each token with `min` to `max` characters.
+/// ("search" -> "se", "sea", "sear", "searc", "search" for 2 and 6)
+///
+/// Index with it to answer autocomplete with plain term queries.
+/// Do not use it in the query pipeline. Tokens shorter than `min`
+/// characters are dropped.
+pub struct EdgeNGramFilter<TCallback> {
+    min: usize,
+    max: usize,
+    callback: TCallback,
+}
+
+impl<TCallback> EdgeNGramFilter<TCallback> {
+    pub fn create(min: usize, max: usize, callback: TCallback) -> Self {
+        assert!(min > 0 && min <= max, "EdgeNGramFilter needs 0 < min <= max");
+        EdgeNGramFilter {
+            min: min,
+            max: max,
+            callback: callback,
+        }
+    }
+}
+
+impl<'a, TCallback> CanApply<&'a str> for EdgeNGramFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: &str) {
+        // Byte offsets after each char
+        let ends = input.char_indices().map(|(i, c)| i + c.len_utf8());
+        for (n, end) in ends.enumerate().map(|(i, end)| (i + 1, end)) {
+            if n > self.max {
+                break;
+            }
+            if n >= self.min {
+                self.callback.apply(input[..end].to_string());
+            }
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<TCallback> CanApply<String> for EdgeNGramFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: String) {
+        CanApply::<&str>::apply(self, input.as_str())
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, TCallback> ToOperands<'a> for EdgeNGramFilter<TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use language::CanApply;
+    use test_utils::Collector;
+    use super::EdgeNGramFilter;
+
+    fn prefixes(min: usize, max: usize, input: &str) -> Vec<String> {
+        let collector = Collector::new();
+        EdgeNGramFilter::create(min, max, collector.clone()).apply(input);
+        collector.terms()
+    }
+
+    #[test]
+    fn leading_prefixes() {
+        assert_eq!(prefixes(2, 6, "search"), vec!["se", "sea", "sear", "searc", "search"]);
+        assert_eq!(prefixes(2, 3, "search"), vec!["se", "sea"]);
+        assert_eq!(prefixes(1, 3, "über"), vec!["ü", "üb", "übe"]);
+        assert!(prefixes(3, 5, "ab").is_empty());
+        assert!(prefixes(1, 5, "").is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use std::str::FromStr;
 
 use perlin_core::utils::seeking_iterator::{PeekableSeekable};
 
-use language::CanApply;
+use language::{CanApply, SkipReason};
 
 use query::{Operand, ToOperands};
 
@@ -37,6 +37,11 @@ impl<'a, TStringCallback, TNumberCallback> CanApply<&'a str>
             self.string_callback.apply(input);
         }
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.string_callback.skip(count, reason);
+        self.number_callback.skip(count, reason);
+    }
 }
 
 impl<'a, TStringCallback, TNumberCallback> ToOperands<'a>
@@ -73,4 +78,8 @@ impl<'a, TCallback> CanApply<&'a str> for ToUsize<TCallback>
             self.callback.apply(number);
         }
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
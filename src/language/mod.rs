@@ -6,22 +6,52 @@ use perlin_core::index::posting::DocId;
 
 use query::{Operand, ToOperands};
 
+#[macro_use]
 mod stemmers;
 pub mod integers;
 mod stopword_filter;
-
-pub use language::stopword_filter::StopwordFilter;
+mod repeat_collapser;
+mod acronym_filter;
+mod case_folder;
+mod ngram_tokenizer;
+mod edge_ngram_filter;
+mod synonym_filter;
+
+pub use language::stopword_filter::{StopwordFilter, Language};
+pub use language::acronym_filter::AcronymFilter;
+pub use language::case_folder::{CaseFolder, CaseFolding, CaseFoldingMismatch, fold_case};
+pub use language::ngram_tokenizer::NGramTokenizer;
+pub use language::edge_ngram_filter::EdgeNGramFilter;
+pub use language::synonym_filter::SynonymFilter;
+pub use language::repeat_collapser::RepeatCollapser;
 pub use language::stemmers::Stemmer;
+pub use rust_stemmers::Algorithm;
+
+/// Why a pipeline element dropped tokens. See `CanApply::skip`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Dropped by `RepeatCollapser`
+    Repeat,
+}
 
 /// The single central trait of the push-based splittable pipeline!
 /// Any element in it can be called passing a typed and generic input and a common value
 pub trait CanApply<Input> {
     type Output;
     fn apply(&mut self, Input);
+
+    /// Called by elements that drop `count` tokens, so buckets can count
+    /// them per document. Elements forward it to their callbacks.
+    /// Ignored by default.
+    fn skip(&mut self, _count: u32, _reason: SkipReason) {}
 }
 
 pub trait PipelineBucket<Term> {
     fn put(&mut self, DocId, Term);
+
+    /// `count` tokens of document `doc_id` were dropped before the next
+    /// `put`. See `CanApply::skip`. Ignored by default.
+    fn skip(&mut self, _doc_id: DocId, _count: u32, _reason: SkipReason) {}
 }
 
 pub struct AlphaNumericTokenizer<TCB> {
@@ -45,6 +75,10 @@ impl<'a, TCB> CanApply<&'a str> for AlphaNumericTokenizer<TCB>
             }
         }
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.cb.skip(count, reason);
+    }
 }
 
 impl<'a, TCB> ToOperands<'a> for AlphaNumericTokenizer<TCB>
@@ -77,6 +111,10 @@ impl<TCallback, T: Debug> CanApply<T> for Debugger<TCallback>
         println!("{:?}", input);
         self.callback.apply(input);
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
 
 impl<'a, TCB> ToOperands<'a> for Debugger<TCB>
@@ -110,6 +148,10 @@ impl<'a, TCallback> CanApply<&'a str> for WhitespaceTokenizer<TCallback>
             self.callback.apply(token);
         }
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
 
 impl<'a, TCallback> ToOperands<'a> for WhitespaceTokenizer<TCallback>
@@ -139,6 +181,23 @@ impl<'a, TCallback> CanApply<&'a str> for LowercaseFilter<TCallback>
     fn apply(&mut self, input: &str) {
         self.callback.apply(input.to_lowercase())
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<TCallback> CanApply<String> for LowercaseFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: String) {
+        self.callback.apply(input.to_lowercase())
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
 
 impl<'a, TCallback> ToOperands<'a> for LowercaseFilter<TCallback>
@@ -176,6 +235,10 @@ impl<'a, TTerm, TBucket> CanApply<TTerm> for Funnel<'a, TTerm, TBucket>
     fn apply(&mut self, input: TTerm) {
         self.bucket.put(self.doc_id, input);
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.bucket.skip(self.doc_id, count, reason);
+    }
 }
 
 #[macro_export]
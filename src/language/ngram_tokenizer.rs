@@ -0,0 +1,140 @@
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Splits its input into character n-grams with `min_n <= n <= max_n`.
+/// Grams are taken over chars, not bytes, and emitted by start position,
+/// shortest first. Inputs shorter than `min_n` characters are skipped
+/// unless `keep_short` is set.
+///
+/// Useful for substring and typo tolerant search on short fields like
+/// product codes. Use the same n in the indexing and the query pipeline.
+pub struct NGramTokenizer<TCallback> {
+    min_n: usize,
+    max_n: usize,
+    keep_short: bool,
+    callback: TCallback,
+}
+
+impl<TCallback> NGramTokenizer<TCallback> {
+    pub fn create(min_n: usize, max_n: usize, callback: TCallback) -> Self {
+        assert!(min_n > 0 && min_n <= max_n, "NGramTokenizer needs 0 < min_n <= max_n");
+        NGramTokenizer {
+            min_n: min_n,
+            max_n: max_n,
+            keep_short: false,
+            callback: callback,
+        }
+    }
+
+    /// Emits n-grams of exactly `n` characters.
+    /// Shorter inputs are emitted whole.
+    pub fn fixed(n: usize, callback: TCallback) -> Self {
+        Self::create(n, n, callback).keep_short(true)
+    }
+
+    /// Emits non-empty inputs shorter than `min_n` characters whole
+    /// instead of skipping them. E.g. for single CJK characters.
+    pub fn keep_short(mut self, keep_short: bool) -> Self {
+        self.keep_short = keep_short;
+        self
+    }
+}
+
+impl<'a, TCallback> CanApply<&'a str> for NGramTokenizer<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: &str) {
+        // Byte offsets of all char boundaries including the end
+        let boundaries = input.char_indices()
+            .map(|(i, _)| i)
+            .chain(Some(input.len()))
+            .collect::<Vec<_>>();
+        let chars = boundaries.len() - 1;
+        if self.keep_short && chars > 0 && chars < self.min_n {
+            self.callback.apply(input.to_string());
+            return;
+        }
+        for start in 0..chars {
+            for n in self.min_n..(self.max_n + 1) {
+                if start + n > chars {
+                    break;
+                }
+                self.callback.apply(input[boundaries[start]..boundaries[start + n]].to_string());
+            }
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, TCallback> ToOperands<'a> for NGramTokenizer<TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::{Posting, DocId};
+
+    use language::{CanApply, Funnel, LowercaseFilter, WhitespaceTokenizer};
+    use query::{self, And, ToOperands};
+    use test_utils::{create_fields, Collector};
+    use super::NGramTokenizer;
+
+    fn grams(min_n: usize, max_n: usize, input: &str) -> Vec<String> {
+        let collector = Collector::new();
+        NGramTokenizer::create(min_n, max_n, collector.clone()).apply(input);
+        collector.terms()
+    }
+
+    #[test]
+    fn grams_over_chars() {
+        assert_eq!(grams(2, 3, "abcd"), vec!["ab", "abc", "bc", "bcd", "cd"]);
+        assert_eq!(grams(2, 2, "übe"), vec!["üb", "be"]);
+        assert_eq!(grams(1, 1, "日本"), vec!["日", "本"]);
+        assert!(grams(3, 4, "ab").is_empty());
+        assert!(grams(2, 3, "").is_empty());
+    }
+
+    #[test]
+    fn fixed_n() {
+        let fixed = |n, input| {
+            let collector = Collector::new();
+            NGramTokenizer::fixed(n, collector.clone()).apply(input);
+            collector.terms()
+        };
+        assert_eq!(fixed(2, "東京都"), vec!["東京", "京都"]);
+        assert_eq!(fixed(3, "日本"), vec!["日本"]);
+        assert_eq!(fixed(2, "ab"), vec!["ab"]);
+        assert!(fixed(2, "").is_empty());
+    }
+
+    #[test]
+    fn substring_match() {
+        let mut fields = create_fields("language/ngram_substring_match", &["code"]);
+        for (doc_id, doc) in ["ABC-1", "XBZ"].iter().enumerate() {
+            let field = fields.get_mut("code").unwrap();
+            let funnel = Funnel::create(DocId(doc_id as u32), field);
+            let mut pipe =
+                WhitespaceTokenizer::create(NGramTokenizer::create(2,
+                                                                   3,
+                                                                   LowercaseFilter::create(funnel)));
+            pipe.apply(*doc);
+        }
+        fields.commit();
+
+        let mut pipe = NGramTokenizer::create(2, 3, query::Funnel::create(&fields));
+        pipe.apply("ab");
+        let mut operands = pipe.to_operands();
+        assert_eq!(And::next(&mut operands), Some(Posting(DocId(0))));
+        assert_eq!(And::next(&mut operands), None);
+    }
+}
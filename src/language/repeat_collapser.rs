@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Collapses repeated tokens as they often occur in scraped or OCR'd text
+/// ("buy buy buy buy now" -> "buy now").
+///
+/// A token is dropped if it is identical to one of the last `window` tokens
+/// that passed through this element. A window of 1 only collapses immediately
+/// adjacent repetitions.
+///
+/// Dropped tokens are reported downstream with `CanApply::skip`.
+/// A `Field` counts them per document
+/// (`Field::collapsed_tokens`) and the derived index records documents with
+/// collapsed tokens in `collapses`.
+///
+/// Place it before any stemmer to collapse on the raw tokens.
+pub struct RepeatCollapser<T, TCallback> {
+    window: usize,
+    recent: VecDeque<T>,
+    collapsed: usize,
+    callback: TCallback,
+}
+
+impl<T, TCallback> RepeatCollapser<T, TCallback> {
+    pub fn create(window: usize, callback: TCallback) -> Self {
+        RepeatCollapser {
+            window: window,
+            recent: VecDeque::with_capacity(window),
+            collapsed: 0,
+            callback: callback,
+        }
+    }
+
+    /// Returns the number of tokens that were dropped so far
+    pub fn collapsed(&self) -> usize {
+        self.collapsed
+    }
+}
+
+impl<T, TCallback> CanApply<T> for RepeatCollapser<T, TCallback>
+    where T: Clone + PartialEq,
+          TCallback: CanApply<T>
+{
+    type Output = TCallback::Output;
+
+    fn apply(&mut self, input: T) {
+        if self.window == 0 {
+            self.callback.apply(input);
+            return;
+        }
+        let repeated = self.recent.contains(&input);
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(input.clone());
+        if repeated {
+            self.collapsed += 1;
+            self.callback.skip(1, SkipReason::Repeat);
+        } else {
+            self.callback.apply(input);
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, T, TCallback> ToOperands<'a> for RepeatCollapser<T, TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_stemmers::Algorithm;
+
+    use language::{CanApply, Stemmer};
+    use test_utils::Collector;
+    use super::RepeatCollapser;
+
+    fn collapse(window: usize, text: &str) -> (Vec<String>, usize) {
+        let collector = Collector::new();
+        let mut collapser = RepeatCollapser::create(window, collector.clone());
+        for token in text.split_whitespace() {
+            collapser.apply(token);
+        }
+        (collector.terms(), collapser.collapsed())
+    }
+
+    fn strings(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn adjacent_runs() {
+        assert_eq!(collapse(1, "buy buy buy buy now"), (strings(&["buy", "now"]), 3));
+        assert_eq!(collapse(1, "buy now buy"), (strings(&["buy", "now", "buy"]), 0));
+    }
+
+    #[test]
+    fn window() {
+        // "buy" reappears within a window of 2 tokens
+        assert_eq!(collapse(2, "buy now buy"), (strings(&["buy", "now"]), 1));
+        // but outside of it here
+        assert_eq!(collapse(2, "buy now cheap buy"),
+                   (strings(&["buy", "now", "cheap", "buy"]), 0));
+        // A window of 0 disables collapsing
+        assert_eq!(collapse(0, "buy buy"), (strings(&["buy", "buy"]), 0));
+    }
+
+    #[test]
+    fn before_stemmer() {
+        // "flies" and "flying" stem to the same term but are different raw tokens
+        let collector = Collector::new();
+        let mut collapser =
+            RepeatCollapser::create(1, Stemmer::create(Algorithm::English, collector.clone()));
+        for token in "flies flies flying".split_whitespace() {
+            collapser.apply(token);
+        }
+        assert_eq!(collapser.collapsed(), 1);
+        assert_eq!(collector.terms().len(), 2);
+    }
+
+    #[test]
+    fn collapsed_counts() {
+        use perlin_core::index::posting::DocId;
+        use language::Funnel;
+        use test_utils::create_fields;
+
+        let mut fields = create_fields("language/repeat_collapser_counts", &["body"]);
+        {
+            let body = fields.get_mut("body").unwrap();
+            let mut collapser = RepeatCollapser::create(1, Funnel::create(DocId(0), body));
+            for token in "buy buy buy now".split_whitespace() {
+                collapser.apply(token.to_string());
+            }
+        }
+        fields.commit();
+        let body = fields.get("body").unwrap();
+        assert_eq!(body.collapsed_tokens(DocId(0)), 2);
+        assert_eq!(body.collapsed_tokens(DocId(1)), 0);
+        // Only kept tokens count towards the document length
+        assert_eq!(body.doc_length(DocId(0)), Some(2));
+    }
+}
@@ -4,7 +4,7 @@ use perlin_core::utils::seeking_iterator::PeekableSeekable;
 use rust_stemmers::{Algorithm, Stemmer as RStemmer};
 
 use query::{Operand, ToOperands};
-use language::CanApply;
+use language::{CanApply, SkipReason};
 
 pub struct Stemmer<TCallback> {
     stemmer: RStemmer,
@@ -18,6 +18,10 @@ impl<TCallback> CanApply<String> for Stemmer<TCallback>
     fn apply(&mut self, input: String) {
         self.callback.apply(self.stemmer.stem(&input).into_owned());
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
 
 impl<'a, TCallback> CanApply<&'a str> for Stemmer<TCallback>
@@ -27,6 +31,10 @@ impl<'a, TCallback> CanApply<&'a str> for Stemmer<TCallback>
     fn apply(&mut self, input: &'a str) {
         self.callback.apply(self.stemmer.stem(input).into_owned());
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
 }
 
 impl<'a, TCallback> ToOperands<'a> for Stemmer<TCallback>
@@ -38,6 +46,8 @@ impl<'a, TCallback> ToOperands<'a> for Stemmer<TCallback>
 }
 
 impl<TCallback> Stemmer<TCallback> {
+    /// Inside `pipeline!` and `query_pipeline!` write `Stemmer(Algorithm::English)`,
+    /// with `Algorithm` from `language`
     pub fn create(language: Algorithm, callback: TCallback) -> Self {
         Stemmer {
             stemmer: RStemmer::create(language),
@@ -45,3 +55,27 @@ impl<TCallback> Stemmer<TCallback> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::DocId;
+
+    use language::{Algorithm, CanApply, Funnel, Stemmer, WhitespaceTokenizer};
+    use test_utils::create_fields;
+
+    #[test]
+    fn collapse_inflections() {
+        let mut fields = create_fields("language/stemmer_collapse_inflections", &["body"]);
+        {
+            let funnel = Funnel::create(DocId(0), fields.get_mut("body").unwrap());
+            WhitespaceTokenizer::create(Stemmer::create(Algorithm::English, funnel))
+                .apply("running runs ran");
+        }
+        fields.commit();
+        let body = fields.get("body").unwrap();
+        // Snowball stems suffixes only. The irregular "ran" stays as it is
+        assert_eq!(body.term_count(), 2);
+        assert!(body.term_id(&"run".to_string()).is_some());
+        assert!(body.term_id(&"ran".to_string()).is_some());
+    }
+}
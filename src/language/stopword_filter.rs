@@ -1,16 +1,114 @@
-use language::CanApply;
+use std::io::{self, BufRead, BufReader};
+use std::fs::File;
+use std::path::Path;
 
-/// Proof of concept stopword filter!
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Languages with a builtin stopword list
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Language {
+    English,
+    German,
+}
+
+const ENGLISH: &'static [&'static str] =
+    &["a", "about", "above", "after", "again", "against", "all", "am", "an", "and", "any",
+      "are", "as", "at", "be", "because", "been", "before", "being", "below", "between", "both",
+      "but", "by", "can", "could", "did", "do", "does", "doing", "down", "during", "each", "few",
+      "for", "from", "further", "had", "has", "have", "having", "he", "her", "here", "hers",
+      "herself", "him", "himself", "his", "how", "i", "if", "in", "into", "is", "it", "its",
+      "itself", "just", "me", "more", "most", "my", "myself", "no", "nor", "not", "now", "of",
+      "off", "on", "once", "only", "or", "other", "our", "ours", "ourselves", "out", "over",
+      "own", "same", "she", "should", "so", "some", "such", "than", "that", "the", "their",
+      "theirs", "them", "themselves", "then", "there", "these", "they", "this", "those",
+      "through", "to", "too", "under", "until", "up", "very", "was", "we", "were", "what",
+      "when", "where", "which", "while", "who", "whom", "why", "will", "with", "would", "you",
+      "your", "yours", "yourself", "yourselves"];
+
+const GERMAN: &'static [&'static str] =
+    &["aber", "alle", "allem", "allen", "aller", "alles", "als", "also", "am", "an", "ander",
+      "andere", "anderem", "anderen", "anderer", "anderes", "auch", "auf", "aus", "bei", "bin",
+      "bis", "bist", "da", "damit", "dann", "das", "dass", "dem", "den", "denn", "der", "des",
+      "dich", "die", "dir", "doch", "dort", "du", "durch", "ein", "eine", "einem", "einen",
+      "einer", "eines", "er", "es", "etwas", "für", "hab", "habe", "haben", "hat", "hatte",
+      "hier", "hin", "ich", "ihm", "ihn", "ihr", "ihre", "im", "in", "ist", "jede", "jeder",
+      "jetzt", "kann", "kein", "keine", "man", "mich", "mir", "mit", "nach", "nicht", "noch",
+      "nun", "nur", "ob", "oder", "ohne", "sehr", "sein", "seine", "sich", "sie", "sind", "so",
+      "über", "um", "und", "uns", "unter", "vom", "von", "vor", "war", "waren", "was", "weil",
+      "wenn", "wer", "wie", "wir", "wird", "zu", "zum", "zur"];
+
+/// Drops stopwords.
+///
+/// Comparison is case insensitive by default. So the filter works in front
+/// of a `LowercaseFilter` as well as behind it.
+/// In a query pipeline stopwords are dropped too and never become operands.
 pub struct StopwordFilter<CB> {
+    // Lowercased if case insensitive. Sorted
     stopwords: Vec<String>,
-    callback: CB
+    case_sensitive: bool,
+    callback: CB,
 }
 
 impl<CB> StopwordFilter<CB> {
-    pub fn create(stopwords: Vec<String>, callback: CB) -> Self{
-        StopwordFilter{
+    pub fn create(stopwords: Vec<String>, callback: CB) -> Self {
+        let mut filter = StopwordFilter {
             stopwords: stopwords,
-            callback: callback
+            case_sensitive: false,
+            callback: callback,
+        };
+        filter.normalize();
+        filter
+    }
+
+    /// Loads stopwords from a file with one stopword per line.
+    /// Empty lines and lines starting with '#' are ignored.
+    pub fn from_file<P: AsRef<Path>>(path: P, callback: CB) -> io::Result<Self> {
+        let mut stopwords = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let word = line.trim();
+            if !word.is_empty() && !word.starts_with('#') {
+                stopwords.push(word.to_string());
+            }
+        }
+        Ok(Self::create(stopwords, callback))
+    }
+
+    /// Uses the builtin stopword list of `language`
+    pub fn for_language(language: Language, callback: CB) -> Self {
+        let stopwords = match language {
+            Language::English => ENGLISH,
+            Language::German => GERMAN,
+        };
+        Self::create(stopwords.iter().map(|word| word.to_string()).collect(), callback)
+    }
+
+    /// Only drops terms exactly matching a stopword.
+    /// E.g. to keep acronyms like "IT" emitted by the `AcronymFilter`.
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self.normalize();
+        self
+    }
+
+    fn normalize(&mut self) {
+        if !self.case_sensitive {
+            for word in &mut self.stopwords {
+                *word = word.to_lowercase();
+            }
+        }
+        self.stopwords.sort();
+        self.stopwords.dedup();
+    }
+
+    fn is_stopword(&self, input: &str) -> bool {
+        if !self.case_sensitive && input.chars().any(|c| c.is_uppercase()) {
+            self.stopwords.binary_search(&input.to_lowercase()).is_ok()
+        } else {
+            self.stopwords.binary_search_by(|word| word.as_str().cmp(input)).is_ok()
         }
     }
 }
@@ -18,10 +116,93 @@ impl<CB> StopwordFilter<CB> {
 
 impl<CB: CanApply<String>> CanApply<String> for StopwordFilter<CB> {
     type Output = CB::Output;
-    
+
     fn apply(&mut self, input: String) {
-        if self.stopwords.binary_search(&input).is_err() {
+        if !self.is_stopword(&input) {
             self.callback.apply(input);
         }
     }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, CB: CanApply<&'a str>> CanApply<&'a str> for StopwordFilter<CB> {
+    type Output = CB::Output;
+
+    fn apply(&mut self, input: &'a str) {
+        if !self.is_stopword(input) {
+            self.callback.apply(input);
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, CB> ToOperands<'a> for StopwordFilter<CB>
+    where CB: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use perlin_core::index::posting::{Posting, DocId};
+
+    use language::{CanApply, Funnel, LowercaseFilter, WhitespaceTokenizer};
+    use query::{self, And, ToOperands};
+    use test_utils::{create_fields, create_test_dir};
+    use super::{StopwordFilter, Language};
+
+    #[test]
+    fn case_insensitive() {
+        let filter = StopwordFilter::for_language(Language::English, ());
+        assert!(filter.is_stopword("the"));
+        assert!(filter.is_stopword("The"));
+        assert!(!filter.is_stopword("fox"));
+        let filter = filter.case_sensitive(true);
+        assert!(!filter.is_stopword("The"));
+        let filter = StopwordFilter::for_language(Language::German, ());
+        assert!(filter.is_stopword("Über"));
+        assert!(!filter.is_stopword("fuchs"));
+    }
+
+    #[test]
+    fn from_file() {
+        let path = create_test_dir("language/stopwords_from_file").join("stopwords.txt");
+        File::create(&path).unwrap().write_all(b"# comment\nFoo\n\n  bar \n").unwrap();
+        let filter = StopwordFilter::from_file(&path, ()).unwrap();
+        assert!(filter.is_stopword("foo"));
+        assert!(filter.is_stopword("bar"));
+        assert!(!filter.is_stopword("# comment"));
+        assert!(StopwordFilter::from_file(path.with_extension("missing"), ()).is_err());
+    }
+
+    #[test]
+    fn query_stopwords() {
+        let mut fields = create_fields("language/query_stopwords", &["body"]);
+        for (doc_id, doc) in ["The quick brown fox", "jumps over the lazy dog"].iter().enumerate() {
+            let funnel = Funnel::create(DocId(doc_id as u32), fields.get_mut("body").unwrap());
+            let filter = StopwordFilter::for_language(Language::English, funnel);
+            WhitespaceTokenizer::create(LowercaseFilter::create(filter)).apply(*doc);
+        }
+        fields.commit();
+
+        // Stopword in front of the LowercaseFilter this time
+        let funnel = query::Funnel::create(&fields);
+        let filter = StopwordFilter::for_language(Language::English, LowercaseFilter::create(funnel));
+        let mut pipe = WhitespaceTokenizer::create(filter);
+        pipe.apply("The fox");
+        let mut operands = pipe.to_operands();
+        assert_eq!(operands.len(), 1);
+        assert_eq!(And::next(&mut operands), Some(Posting(DocId(0))));
+    }
 }
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use language::{CanApply, SkipReason};
+use query::{Operand, ToOperands};
+
+/// Forwards each token followed by its synonyms.
+///
+/// Lookups are exact, so place it behind a `LowercaseFilter` and use
+/// lowercase keys. Synonyms are not expanded again.
+/// In a query pipeline every synonym becomes an operand of its own. These
+/// are combined like all query terms, so documents containing any of them
+/// match and documents containing more of them rank higher.
+pub struct SynonymFilter<TCallback> {
+    synonyms: HashMap<String, Vec<String>>,
+    callback: TCallback,
+}
+
+impl<TCallback> SynonymFilter<TCallback> {
+    pub fn create(synonyms: HashMap<String, Vec<String>>, callback: TCallback) -> Self {
+        SynonymFilter {
+            synonyms: synonyms,
+            callback: callback,
+        }
+    }
+}
+
+impl<'a, TCallback> CanApply<&'a str> for SynonymFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: &str) {
+        self.callback.apply(input.to_string());
+        if let Some(synonyms) = self.synonyms.get(input) {
+            for synonym in synonyms {
+                self.callback.apply(synonym.clone());
+            }
+        }
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<TCallback> CanApply<String> for SynonymFilter<TCallback>
+    where TCallback: CanApply<String>
+{
+    type Output = TCallback::Output;
+    fn apply(&mut self, input: String) {
+        CanApply::<&str>::apply(self, &input)
+    }
+
+    fn skip(&mut self, count: u32, reason: SkipReason) {
+        self.callback.skip(count, reason);
+    }
+}
+
+impl<'a, TCallback> ToOperands<'a> for SynonymFilter<TCallback>
+    where TCallback: ToOperands<'a>
+{
+    fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>> {
+        self.callback.to_operands()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use perlin_core::index::posting::{Posting, DocId};
+
+    use language::{CanApply, Funnel, LowercaseFilter, WhitespaceTokenizer};
+    use query::{self, ToOperands, WeightingOperator};
+    use test_utils::create_fields;
+    use super::SynonymFilter;
+
+    fn synonyms() -> HashMap<String, Vec<String>> {
+        let mut synonyms = HashMap::new();
+        synonyms.insert("tv".to_string(), vec!["television".to_string()]);
+        synonyms
+    }
+
+    #[test]
+    fn query_time_expansion() {
+        let mut fields = create_fields("language/synonym_query_time", &["body"]);
+        let docs = ["Television set", "TV guide", "radio"];
+        for (doc_id, doc) in docs.iter().enumerate() {
+            let funnel = Funnel::create(DocId(doc_id as u32), fields.get_mut("body").unwrap());
+            WhitespaceTokenizer::create(LowercaseFilter::create(funnel)).apply(*doc);
+        }
+        fields.commit();
+
+        let run = |synonyms| {
+            let filter = SynonymFilter::create(synonyms, query::Funnel::create(&fields));
+            let mut pipe = WhitespaceTokenizer::create(LowercaseFilter::create(filter));
+            pipe.apply("TV");
+            let mut result = WeightingOperator::create(pipe.to_operands(), vec![])
+                .collect::<Vec<_>>();
+            result.sort();
+            result
+        };
+        assert_eq!(run(HashMap::new()), vec![Posting(DocId(1))]);
+        assert_eq!(run(synonyms()), vec![Posting(DocId(0)), Posting(DocId(1))]);
+    }
+
+    #[test]
+    fn index_time_expansion() {
+        let mut fields = create_fields("language/synonym_index_time", &["body"]);
+        for (doc_id, doc) in ["tv guide", "radio"].iter().enumerate() {
+            let funnel = Funnel::create(DocId(doc_id as u32), fields.get_mut("body").unwrap());
+            WhitespaceTokenizer::create(SynonymFilter::create(synonyms(), funnel)).apply(*doc);
+        }
+        fields.commit();
+
+        let mut funnel = query::Funnel::create(&fields);
+        funnel.apply("television".to_string());
+        let result = WeightingOperator::create(funnel.to_operands(), vec![]).collect::<Vec<_>>();
+        assert_eq!(result, vec![Posting(DocId(0))]);
+    }
+}
@@ -7,20 +7,30 @@
 //! The previously released version 0.1 differs massively from this. Please
 //! refer to tag 'v0.1' for the code  and
 //! [https://doc.perlin-ir.org/v0.1/perlin/index.html] for documentation
+//!
+//! Lifecycle events are logged through the `log` facade with the targets
+//! `perlin::index` (commits), `perlin::storage` (files and page caches) and
+//! `perlin::query` (once per query, never per posting).
 #[cfg(test)]
 #[macro_use]
 extern crate perlin_derive;
-extern crate perlin_core;
+pub extern crate perlin_core;
 extern crate rust_stemmers;
+extern crate unicode_normalization;
+#[macro_use]
+extern crate log;
 
 #[macro_use]
-mod utils;
+pub mod utils;
 #[macro_use]
 pub mod language;
 #[macro_use]
 pub mod query;
 pub mod document_index;
 pub mod field;
+pub mod validation;
+pub mod metadata;
+pub mod memory;
 
 #[cfg(test)]
 pub mod test_utils;
@@ -0,0 +1,50 @@
+//! Estimates of the heap memory held by the components of this crate.
+//!
+//! Numbers are computed from `size_of` and tracked capacities, not from the
+//! allocator. They are estimates. Memory held by perlin-core's `Index`
+//! (vocabulary, hot chunks, page caches) is not included.
+use std::fmt;
+
+/// Tree of memory estimates. The bytes of a node are the sum of its children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryReport {
+    pub name: String,
+    pub bytes: usize,
+    pub children: Vec<MemoryReport>,
+}
+
+impl MemoryReport {
+    pub fn leaf(name: &str, bytes: usize) -> Self {
+        MemoryReport {
+            name: name.to_string(),
+            bytes: bytes,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn node(name: &str, children: Vec<MemoryReport>) -> Self {
+        MemoryReport {
+            name: name.to_string(),
+            bytes: children.iter().map(|child| child.bytes).sum(),
+            children: children,
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        writeln!(f, "{:indent$}{}: {} bytes", "", self.name, self.bytes, indent = depth * 2)?;
+        for child in &self.children {
+            child.fmt_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+pub trait MemoryUsage {
+    fn memory_report(&self) -> MemoryReport;
+}
@@ -0,0 +1,286 @@
+//! Small key-value store for application bookkeeping next to an index
+//! (e.g. the last ingested offset or a hash of the pipeline configuration).
+//!
+//! It is one file, loaded fully into memory. `persist` writes a temporary
+//! file and renames it over the old one, so readers see either the old or the
+//! new state, never a mix. The derived index persists its metadata after all
+//! fields were committed. Both steps are not atomic together: if persisting
+//! fails, the fields stay committed with the previous metadata on disk.
+//!
+//! The fields of an index are not reopened from disk, so every index starts
+//! empty. Metadata found when creating one belongs to an earlier index and is
+//! discarded.
+//!
+//! Keys starting with "perlin." are reserved for the index itself.
+use std::io;
+use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+/// Maximum size of a single value in bytes
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+/// Key of the `CaseFolding` recorded by the derived index's `set_case_folding`
+pub const CASE_FOLDING_KEY: &'static str = "perlin.case_folding";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    ValueTooLarge,
+}
+
+pub struct Metadata {
+    path: PathBuf,
+    entries: HashMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+    /// Loads the metadata stored at `path`. Empty if the file does not exist.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let mut bytes = Vec::new();
+            File::open(path)?.read_to_end(&mut bytes)?;
+            entries = parse(&bytes)
+                .map_err(|err| {
+                    error!(target: "perlin::storage", "Corrupt metadata in {:?}: {}", path, err);
+                    err
+                })?;
+        }
+        debug!(target: "perlin::storage",
+               "Loaded {} metadata entries from {:?}",
+               entries.len(),
+               path);
+        Ok(Metadata {
+            path: path.to_path_buf(),
+            entries: entries,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.entries.get(key).map(|value| value.as_slice())
+    }
+
+    pub fn set(&mut self, key: &str, value: &[u8]) -> Result<(), MetadataError> {
+        if value.len() > MAX_VALUE_SIZE {
+            return Err(MetadataError::ValueTooLarge);
+        }
+        self.entries.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.entries.remove(key)
+    }
+
+    /// Drops all entries, e.g. because they describe an earlier index.
+    /// Takes effect on disk with the next `persist`.
+    pub fn discard(&mut self) {
+        if !self.entries.is_empty() {
+            warn!(target: "perlin::storage",
+                  "Discarding {} stale metadata entries in {:?}",
+                  self.entries.len(),
+                  self.path);
+            self.entries.clear();
+        }
+    }
+
+    /// Returns all keys in ascending order
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = self.entries.keys().map(|key| key.as_str()).collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    pub fn persist(&self) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        write_u32(&mut bytes, self.entries.len() as u32);
+        // Sorted, so identical metadata results in identical files
+        for key in self.keys() {
+            write_bytes(&mut bytes, key.as_bytes());
+            write_bytes(&mut bytes, &self.entries[key]);
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        debug!(target: "perlin::storage",
+               "Persisted {} metadata entries to {:?}",
+               self.entries.len(),
+               self.path);
+        Ok(())
+    }
+}
+
+fn parse(mut bytes: &[u8]) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut entries = HashMap::new();
+    let count = read_u32(&mut bytes)?;
+    for _ in 0..count {
+        let key = read_bytes(&mut bytes)?;
+        let value = read_bytes(&mut bytes)?;
+        let key = String::from_utf8(key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid key"))?;
+        entries.insert(key, value);
+    }
+    Ok(entries)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        bytes.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    write_u32(bytes, value.len() as u32);
+    bytes.extend_from_slice(value);
+}
+
+fn read_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    bytes.read_exact(&mut buf)?;
+    Ok(buf.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+fn read_bytes(bytes: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = read_u32(bytes)? as usize;
+    if len > bytes.len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated metadata"));
+    }
+    let mut value = vec![0; len];
+    bytes.read_exact(&mut value)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_utils::create_test_dir;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path = create_test_dir("metadata/round_trip").join("metadata.bin");
+        let mut metadata = Metadata::load(&path).unwrap();
+        metadata.set("offset", &[1, 2, 3]).unwrap();
+        metadata.set("version", b"").unwrap();
+        metadata.set("pipeline", "ünïcode".as_bytes()).unwrap();
+        metadata.persist().unwrap();
+
+        let loaded = Metadata::load(&path).unwrap();
+        assert_eq!(loaded.keys(), vec!["offset", "pipeline", "version"]);
+        assert_eq!(loaded.get("offset"), Some(&[1u8, 2, 3][..]));
+        assert_eq!(loaded.get("version"), Some(&[][..]));
+        assert_eq!(loaded.get("pipeline"), Some("ünïcode".as_bytes()));
+        assert_eq!(loaded.get("missing"), None);
+    }
+
+    #[test]
+    fn unpersisted_changes() {
+        let path = create_test_dir("metadata/unpersisted_changes").join("metadata.bin");
+        let mut metadata = Metadata::load(&path).unwrap();
+        metadata.set("offset", &[1]).unwrap();
+        metadata.persist().unwrap();
+        metadata.set("offset", &[2]).unwrap();
+        // Not persisted, e.g. because the index commit failed
+        assert_eq!(Metadata::load(&path).unwrap().get("offset"), Some(&[1u8][..]));
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        use std::thread;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let path = create_test_dir("metadata/concurrent_readers").join("metadata.bin");
+        // Every generation stores the same value under all keys
+        let publish = |metadata: &mut Metadata, generation: u8| {
+            for key in &["a", "b", "c"] {
+                metadata.set(key, &vec![generation; 4096]).unwrap();
+            }
+            metadata.persist().unwrap();
+        };
+        let mut metadata = Metadata::load(&path).unwrap();
+        publish(&mut metadata, 0);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut loads = 0;
+                    while !done.load(Ordering::SeqCst) || loads == 0 {
+                        let loaded = Metadata::load(&path).unwrap();
+                        assert_eq!(loaded.keys(), vec!["a", "b", "c"]);
+                        let a = loaded.get("a").unwrap();
+                        assert!(a.iter().all(|b| *b == a[0]));
+                        assert_eq!(loaded.get("b"), Some(a));
+                        assert_eq!(loaded.get("c"), Some(a));
+                        loads += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for generation in 1..200 {
+            publish(&mut metadata, generation);
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn discard() {
+        let path = create_test_dir("metadata/discard").join("metadata.bin");
+        let mut metadata = Metadata::load(&path).unwrap();
+        metadata.set("offset", &[1]).unwrap();
+        metadata.persist().unwrap();
+
+        let mut metadata = Metadata::load(&path).unwrap();
+        metadata.discard();
+        assert!(metadata.keys().is_empty());
+        // Still on disk until persisted
+        assert_eq!(Metadata::load(&path).unwrap().keys(), vec!["offset"]);
+        metadata.persist().unwrap();
+        assert!(Metadata::load(&path).unwrap().keys().is_empty());
+    }
+
+    #[test]
+    fn corrupt() {
+        use std::fs::{self, File};
+        use std::io::Write;
+        use log::Level;
+        use test_utils::capture_logs;
+
+        let path = create_test_dir("metadata/corrupt").join("metadata.bin");
+        let mut metadata = Metadata::load(&path).unwrap();
+        metadata.set("offset", &[1, 2, 3]).unwrap();
+        metadata.persist().unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        // Truncated in the count, in a length and in a value
+        for &len in &[2, 6, bytes.len() - 1] {
+            File::create(&path).unwrap().write_all(&bytes[..len]).unwrap();
+            let mut result = None;
+            let logs = capture_logs(|| result = Some(Metadata::load(&path)));
+            assert!(result.unwrap().is_err());
+            assert_eq!(logs.len(), 1);
+            assert_eq!((logs[0].0.as_str(), logs[0].1), ("perlin::storage", Level::Error));
+            assert!(logs[0].2.starts_with("Corrupt metadata"));
+        }
+    }
+
+    #[test]
+    fn size_cap() {
+        let path = create_test_dir("metadata/size_cap").join("metadata.bin");
+        let mut metadata = Metadata::load(&path).unwrap();
+        assert!(metadata.set("max", &vec![0; MAX_VALUE_SIZE]).is_ok());
+        assert_eq!(metadata.set("too_large", &vec![0; MAX_VALUE_SIZE + 1]),
+                   Err(MetadataError::ValueTooLarge));
+        assert!(metadata.get("too_large").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use perlin_core::index::posting::{Posting, DocId};
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use query::{And, MinShouldMatch, Operand, Weight};
+
+/// Approximate number of distinct documents
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ApproxCount {
+    pub estimate: f64,
+    /// Standard error relative to the estimate
+    pub relative_error: f64,
+}
+
+/// HyperLogLog sketch over DocIds with 2^precision registers
+///
+/// Memory stays at 2^precision bytes no matter how many DocIds are added.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Precision is clamped to 4..16
+    pub fn new(precision: u8) -> Self {
+        let precision = precision.max(4).min(16);
+        HyperLogLog {
+            precision: precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    pub fn add(&mut self, doc_id: DocId) {
+        let mut hasher = DefaultHasher::new();
+        doc_id.0.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    /// Merges `other` into this sketch. Both need the same precision.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert_eq!(self.precision, other.precision);
+        for (a, b) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *b > *a {
+                *a = *b;
+            }
+        }
+    }
+
+    pub fn count(&self) -> ApproxCount {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1. + 1.079 / m),
+        };
+        let sum = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum::<f64>();
+        let mut estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // Small range correction
+        if estimate <= 2.5 * m && zeros > 0 {
+            estimate = m * (m / zeros as f64).ln();
+        }
+        ApproxCount {
+            estimate: estimate,
+            relative_error: 1.04 / m.sqrt(),
+        }
+    }
+}
+
+/// Counts the distinct DocIds yielded by `postings` approximately.
+pub fn approx_count<I: Iterator<Item = Posting>>(postings: I, precision: u8) -> ApproxCount {
+    let mut sketch = HyperLogLog::new(precision);
+    for Posting(doc_id) in postings {
+        sketch.add(doc_id);
+    }
+    sketch.count()
+}
+
+/// Approximately counts the documents matching any of `operands`, all
+/// `filters` and none of `excluded`. These are the results of an exhaustive
+/// query, so subsets `WeightingOperator` would cut off are counted as well.
+///
+/// The postings are merged on the fly and never collected. Memory stays at
+/// the sketch's registers no matter how many documents match.
+///
+/// There are no precomputed per-term sketches: every posting of the operands
+/// is decoded once, just like an exhaustive run would. The count saves
+/// memory and weighting, not decoding.
+pub fn approx_count_union<'a>(operands: Vec<PeekableSeekable<Operand<'a>>>,
+                              filters: Vec<PeekableSeekable<Operand<'a>>>,
+                              excluded: Vec<PeekableSeekable<Operand<'a>>>,
+                              precision: u8)
+                              -> ApproxCount {
+    let mut conjunction = filters;
+    if !operands.is_empty() {
+        conjunction.push(PeekableSeekable::new(Operand::Any(Weight(0.),
+                                                            MinShouldMatch::create(operands, 1),
+                                                            String::new(),
+                                                            String::new())));
+    }
+    let mut excluded = MinShouldMatch::create(excluded, 1);
+    let mut next_excluded = excluded.next();
+    let mut sketch = HyperLogLog::new(precision);
+    while let Some(posting) = And::next(&mut conjunction) {
+        let behind = match next_excluded {
+            Some(ref excluded) => excluded.0 < posting.0,
+            None => false,
+        };
+        if behind {
+            next_excluded = excluded.next_seek(&posting);
+        }
+        if next_excluded.as_ref() != Some(&posting) {
+            sketch.add(posting.0);
+        }
+    }
+    sketch.count()
+}
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::{Posting, DocId};
+    use super::*;
+
+    fn within_bound(count: ApproxCount, actual: usize) -> bool {
+        // Three standard errors
+        (count.estimate - actual as f64).abs() <= 3. * count.relative_error * actual as f64
+    }
+
+    #[test]
+    fn estimation_error() {
+        for &precision in &[8, 10, 12, 14] {
+            for &n in &[100, 10_000, 200_000] {
+                let count = approx_count((0..n).map(|i| Posting(DocId(i))), precision);
+                assert!(within_bound(count, n as usize),
+                        format!("precision {} n {} estimate {:?}", precision, n, count));
+            }
+        }
+    }
+
+    #[test]
+    fn duplicates() {
+        let count = approx_count((0..50_000).map(|i| Posting(DocId(i % 1000))), 12);
+        assert!(within_bound(count, 1000));
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = HyperLogLog::new(12);
+        let mut b = HyperLogLog::new(12);
+        let mut both = HyperLogLog::new(12);
+        for i in 0..20_000 {
+            if i % 3 == 0 {
+                a.add(DocId(i));
+            } else {
+                b.add(DocId(i));
+            }
+            both.add(DocId(i));
+        }
+        a.merge(&b);
+        assert_eq!(a, both);
+    }
+}
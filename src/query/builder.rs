@@ -0,0 +1,144 @@
+use std::hash::Hash;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use query::{ChainingOperator, MinShouldMatch, Operand, Query, Weight};
+use field::Field;
+
+/// A term in a single field that a query must, may or must not match
+#[derive(Debug, Clone)]
+pub struct Clause {
+    pub operator: ChainingOperator,
+    pub field: String,
+    /// Alternatives. The clause matches documents matching any of them.
+    /// Usually there is only one.
+    pub terms: Vec<String>,
+}
+
+/// Builds a `Query` with clauses on specific fields.
+///
+/// ```ignore
+/// let query = QueryBuilder::new("rust search")
+///     .must("title", "rust")
+///     .must_not("lang", "java")
+///     .build();
+/// index.run_query(query);
+/// ```
+///
+/// The query string goes through the query pipeline as usual. Clause terms
+/// do not. They have to be given as they were indexed (e.g. stemmed).
+pub struct QueryBuilder<'a> {
+    query: Query<'a>,
+}
+
+impl<'a> QueryBuilder<'a> {
+    pub fn new(query: &'a str) -> Self {
+        QueryBuilder { query: Query::new(query) }
+    }
+
+    /// Only documents containing `term` in `field` are yielded.
+    /// If the term is unknown, nothing is yielded.
+    pub fn must(self, field: &str, term: &str) -> Self {
+        self.clause(ChainingOperator::Must, field, term)
+    }
+
+    /// Documents containing `term` in `field` are yielded as if the term
+    /// was part of the query string
+    pub fn may(self, field: &str, term: &str) -> Self {
+        self.clause(ChainingOperator::May, field, term)
+    }
+
+    /// Documents containing `term` in `field` are never yielded
+    pub fn must_not(self, field: &str, term: &str) -> Self {
+        self.clause(ChainingOperator::MustNot, field, term)
+    }
+
+    /// Only documents containing any of `terms` in `field` are yielded.
+    pub fn must_any(self, field: &str, terms: &[&str]) -> Self {
+        self.any_as(ChainingOperator::Must, field, terms)
+    }
+
+    /// Like `must_any`, but with any operator
+    pub fn any_as(mut self, operator: ChainingOperator, field: &str, terms: &[&str]) -> Self {
+        self.query.clauses.push(Clause {
+            operator: operator,
+            field: field.to_string(),
+            terms: terms.iter().map(|term| term.to_string()).collect(),
+        });
+        self
+    }
+
+    /// Same as `must`. Like all filters, must clauses restrict the results
+    /// without changing their order.
+    pub fn filter(self, field: &str, term: &str) -> Self {
+        self.must(field, term)
+    }
+
+    pub fn exhaustive(mut self, exhaustive: bool) -> Self {
+        self.query = self.query.exhaustive(exhaustive);
+        self
+    }
+
+    pub fn build(self) -> Query<'a> {
+        self.query
+    }
+
+    fn clause(self, operator: ChainingOperator, field: &str, term: &str) -> Self {
+        self.any_as(operator, field, &[term])
+    }
+}
+
+/// Returns the operand for `term` in `field`. `None` if the term is unknown
+/// or `query` lacks the capability for `field`.
+/// The weight is multiplied with the field's boost in `query`.
+///
+/// Unlike `Funnel`, operands with a weight of zero are returned as well.
+/// A must clause on a term contained in every document must not be dropped.
+pub fn clause_operand<'a, T>(field: &'a Field<T>,
+                             term: &T,
+                             query: &Query)
+                             -> Option<PeekableSeekable<Operand<'a>>>
+    where T: Hash + Eq + Ord + Debug + ToString
+{
+    if !field.is_visible_to(&query.capabilities) {
+        return None;
+    }
+    let w = query.field_boost(field.name()) / field.term_doc_ratio;
+    field.query_term(term).map(|(idf, decoder)| {
+        PeekableSeekable::new(Operand::Term(Weight(idf * w),
+                                            decoder,
+                                            term.to_string(),
+                                            field.name().to_string()))
+    })
+}
+
+/// Returns the operand for `clause` on `field`. `None` if `query` lacks the
+/// capability for `field` or no alternative of the clause can match, i.e.
+/// none parses into `T` or is known.
+///
+/// Several alternatives are unioned into one operand.
+pub fn resolve_clause<'a, T>(field: &'a Field<T>,
+                             clause: &Clause,
+                             query: &Query)
+                             -> Option<PeekableSeekable<Operand<'a>>>
+    where T: Hash + Eq + Ord + Debug + ToString + FromStr
+{
+    if !field.is_visible_to(&query.capabilities) {
+        return None;
+    }
+    let mut operands = clause.terms
+        .iter()
+        .filter_map(|term| term.parse().ok())
+        .filter_map(|term| clause_operand(field, &term, query))
+        .collect::<Vec<_>>();
+    if operands.len() <= 1 {
+        return operands.pop();
+    }
+    let weight = operands.iter().map(|op| op.inner().weight()).max().unwrap_or(Weight(0.));
+    Some(PeekableSeekable::new(Operand::Any(weight,
+                                            MinShouldMatch::create(operands, 1),
+                                            clause.terms.join(" OR "),
+                                            field.name().to_string())))
+}
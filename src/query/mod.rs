@@ -1,17 +1,33 @@
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::fmt::Debug;
+use std::hash::Hash;
 
-use perlin_core::index::posting::{Posting, PostingIterator, PostingDecoder};
+use perlin_core::index::posting::{Posting, PostingDecoder};
 use perlin_core::utils::seeking_iterator::{PeekableSeekable, SeekingIterator};
 use perlin_core::utils::progress::Progress;
 
-pub use query::operators::{And, Funnel, Combinator};
+use field::Field;
+use language::{CaseFolding, CaseFoldingMismatch};
+
+pub use query::operators::{And, Funnel, Combinator, MinShouldMatch};
+pub use query::yielding::{YieldingResults, collect_top_yielding};
+pub use query::approx_count::{HyperLogLog, ApproxCount, approx_count, approx_count_union};
+pub use query::scored::{ScoredOperator, Bm25};
+pub use query::builder::{Clause, QueryBuilder, clause_operand, resolve_clause};
+pub use query::parser::ParseError;
 
 #[macro_use]
 pub mod query_pipeline;
 mod operators;
+mod yielding;
+mod approx_count;
+mod scored;
+mod builder;
+mod parser;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ChainingOperator {
     Must,
     May,
@@ -29,9 +45,47 @@ impl Ord for Weight {
     }
 }
 
+/// Options controlling how `WeightingOperator` enumerates operand subsets
+#[derive(Debug, Copy, Clone)]
+pub struct WeightingOptions {
+    /// Subsets whose summed weight is below this ratio of the summed weight
+    /// of all operands end the enumeration.
+    ///
+    /// `None` disables the cutoff. All 2^n subsets are enumerated then, which
+    /// yields every matching document but gets expensive quickly as the
+    /// number of operands (query terms * fields) grows.
+    pub min_subset_weight_ratio: Option<f32>,
+    /// Maximum number of postings to emit. Once it is reached the operator
+    /// stops and drops its operands, so no further subsets are set up and
+    /// no further postings are decoded.
+    pub limit: Option<usize>,
+}
+
+impl Default for WeightingOptions {
+    fn default() -> Self {
+        WeightingOptions {
+            min_subset_weight_ratio: Some(0.01),
+            limit: None,
+        }
+    }
+}
+
+impl WeightingOptions {
+    /// Options that enumerate every matching document
+    pub fn exhaustive() -> Self {
+        WeightingOptions {
+            min_subset_weight_ratio: None,
+            limit: None,
+        }
+    }
+}
+
 pub struct WeightingOperator<'a> {
     max_weight: Weight,
+    options: WeightingOptions,
     already_emitted: Vec<Posting>,
+    // Postings that are never emitted. Sorted
+    excluded: Vec<Posting>,
     filters: Vec<PeekableSeekable<Operand<'a>>>,
     operands: Vec<PeekableSeekable<Operand<'a>>>,
     current_operands: Option<Vec<PeekableSeekable<Operand<'a>>>>,
@@ -42,6 +96,12 @@ impl<'a> Iterator for WeightingOperator<'a> {
     type Item = Posting;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(limit) = self.options.limit {
+            if self.already_emitted.len() >= limit {
+                self.current_operands = None;
+                return None;
+            }
+        }
         loop {
             let next = self.internal_next();
             if next.is_none() {
@@ -50,6 +110,10 @@ impl<'a> Iterator for WeightingOperator<'a> {
 
             let posting = next.unwrap();
 
+            if self.excluded.binary_search(&posting).is_ok() {
+                continue;
+            }
+
             let index = match self.already_emitted.binary_search(&posting) {
                 Ok(_) => {
                     // We already emitted that posting
@@ -122,7 +186,7 @@ impl<'a> WeightingOperator<'a> {
                             }
                         }
 
-                        if new_current_operands.is_empty() || (curr_weight.0 < (0.01 * self.max_weight.0))  {
+                        if new_current_operands.is_empty() || self.below_cutoff(curr_weight) {
                             return None;
                         }
                         // TODO: Sort current operands by length(!)
@@ -144,6 +208,14 @@ impl<'a> WeightingOperator<'a> {
         }
     }
 
+    fn below_cutoff(&self, weight: Weight) -> bool {
+        if let Some(ratio) = self.options.min_subset_weight_ratio {
+            weight.0 < ratio * self.max_weight.0
+        } else {
+            false
+        }
+    }
+
     // TODO: Think about something more correct(!)
     pub fn progress(&self) -> Progress {
         if let Some(ref operands) = self.current_operands {
@@ -156,16 +228,34 @@ impl<'a> WeightingOperator<'a> {
         }
     }
 
-    pub fn create(mut operands: Vec<PeekableSeekable<Operand<'a>>>,
+    /// Creates the operator with the default `WeightingOptions`.
+    /// Subsets below 1% of the total weight are not enumerated.
+    pub fn create(operands: Vec<PeekableSeekable<Operand<'a>>>,
                   filters: Vec<PeekableSeekable<Operand<'a>>>)
                   -> Self {
+        Self::create_with_options(operands, filters, WeightingOptions::default())
+    }
+
+    pub fn create_with_options(mut operands: Vec<PeekableSeekable<Operand<'a>>>,
+                               filters: Vec<PeekableSeekable<Operand<'a>>>,
+                               options: WeightingOptions)
+                               -> Self {
+        if log_enabled!(target: "perlin::query", ::log::Level::Debug) {
+            debug!(target: "perlin::query",
+                   "Weighting operands {:?} with {} filters and {:?}",
+                   operands.iter().map(|op| op.inner()).collect::<Vec<_>>(),
+                   filters.len(),
+                   options);
+        }
         operands.sort_by_key(|op| op.inner().weight());
         let mut current_operands = operands.clone();
         current_operands.append(&mut filters.clone());
         let max_weight = operands.iter().fold(Weight(0.), |acc, ref op| Weight(acc.0 + op.inner().weight().0));
         WeightingOperator {
             already_emitted: Vec::new(),
+            excluded: Vec::new(),
             max_weight,
+            options,
             filters,
             operands,
             current_operands: Some(current_operands),
@@ -174,11 +264,26 @@ impl<'a> WeightingOperator<'a> {
         }
 
     }
+
+    /// Postings matched by any of `operands` are never emitted.
+    ///
+    /// The operands are decoded completely right away, before the first
+    /// posting is emitted. A `limit` does not bound that work.
+    pub fn exclude(mut self, operands: Vec<PeekableSeekable<Operand<'a>>>) -> Self {
+        for operand in operands {
+            self.excluded.extend(operand.inner().clone());
+        }
+        self.excluded.sort();
+        self.excluded.dedup();
+        self
+    }
 }
 
 #[derive(Clone)]
 pub enum Operand<'a> {
     Term(Weight, PostingDecoder<'a>, String, String),
+    /// Union of the postings of several terms, e.g. a range of terms
+    Any(Weight, MinShouldMatch<'a>, String, String),
 }
 
 impl<'a> fmt::Debug for Operand<'a> {
@@ -191,6 +296,13 @@ impl<'a> fmt::Debug for Operand<'a> {
                        field,
                        weight)
             }
+            Operand::Any(weight, _, ref terms, ref field) => {
+                write!(f,
+                       "Querying any of {:?} on field {:?} with weight {:?}",
+                       terms,
+                       field,
+                       weight)
+            }
         }
     }
 }
@@ -200,7 +312,14 @@ impl<'a> Iterator for Operand<'a> {
 
     fn next(&mut self) -> Option<Posting> {
         match *self {
-            Operand::Term(_, ref mut decoder, _, _) => decoder.next(),
+            Operand::Term(_, ref mut decoder, _, _) => {
+                let posting = decoder.next();
+                if posting.is_some() {
+                    count_event!("decoded posting");
+                }
+                posting
+            }
+            Operand::Any(_, ref mut any, _, _) => any.next(),
         }
     }
 }
@@ -210,7 +329,14 @@ impl<'a> SeekingIterator for Operand<'a> {
 
     fn next_seek(&mut self, other: &Posting) -> Option<Posting> {
         match *self {
-            Operand::Term(_, ref mut decoder, _, _) => decoder.next_seek(other),
+            Operand::Term(_, ref mut decoder, _, _) => {
+                let posting = decoder.next_seek(other);
+                if posting.is_some() {
+                    count_event!("decoded posting");
+                }
+                posting
+            }
+            Operand::Any(_, ref mut any, _, _) => any.next_seek(other),
         }
     }
 }
@@ -218,13 +344,42 @@ impl<'a> SeekingIterator for Operand<'a> {
 impl<'a> Operand<'a> {
     pub fn weight(&self) -> Weight {
         match *self {
-            Operand::Term(w, _, _, _) => w,
+            Operand::Term(w, _, _, _) |
+            Operand::Any(w, _, _, _) => w,
+        }
+    }
+
+    /// The queried term. For `Any` a description of the queried terms
+    pub fn term(&self) -> &str {
+        match *self {
+            Operand::Term(_, _, ref term, _) |
+            Operand::Any(_, _, ref term, _) => term,
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        match *self {
+            Operand::Term(_, _, _, ref field) |
+            Operand::Any(_, _, _, ref field) => field,
+        }
+    }
+
+    /// Multiplies the weight with `factor`, e.g. a field boost
+    pub fn boosted(self, factor: f32) -> Self {
+        match self {
+            Operand::Term(w, decoder, term, field) => {
+                Operand::Term(Weight(w.0 * factor), decoder, term, field)
+            }
+            Operand::Any(w, any, term, field) => {
+                Operand::Any(Weight(w.0 * factor), any, term, field)
+            }
         }
     }
 
     pub fn progress(&self) -> Progress {
         match *self {
             Operand::Term(_, ref decoder, _, _) => decoder.progress(),
+            Operand::Any(_, ref any, _, _) => any.progress(),
         }
     }
 }
@@ -233,10 +388,34 @@ pub trait ToOperands<'a> {
     fn to_operands(self) -> Vec<PeekableSeekable<Operand<'a>>>;
 }
 
+/// Decides which document is kept if several documents with the same
+/// external id match a query
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DedupePolicy {
+    /// Keep the first emitted, i.e. highest ranked, document
+    HighestRanked,
+    /// Keep only the document with the highest DocId.
+    /// Older versions are suppressed even if the newest one does not match.
+    Newest,
+}
+
 #[derive(Clone)]
 pub struct Query<'a> {
     pub query: &'a str,
     pub filter: Vec<PeekableSeekable<Operand<'a>>>,
+    pub exhaustive: bool,
+    /// Capabilities granted to this query. See `Field::required_capability`
+    pub capabilities: Vec<String>,
+    pub dedupe_by_external_id: bool,
+    pub dedupe_policy: DedupePolicy,
+    /// Field specific clauses. See `QueryBuilder`
+    pub clauses: Vec<Clause>,
+    /// Factors the weights of operands on a field are multiplied with
+    pub boosts: HashMap<String, f32>,
+    /// Maximum number of results. See `WeightingOptions::limit`
+    pub limit: Option<usize>,
+    /// Case folding of the query pipeline. See `case_folding`
+    pub case_folding: Option<CaseFolding>,
 }
 
 impl<'a> Query<'a> {
@@ -244,19 +423,123 @@ impl<'a> Query<'a> {
         Query {
             query: query,
             filter: vec![],
+            exhaustive: false,
+            capabilities: vec![],
+            dedupe_by_external_id: false,
+            dedupe_policy: DedupePolicy::HighestRanked,
+            clauses: vec![],
+            boosts: HashMap::new(),
+            limit: None,
+            case_folding: None,
         }
     }
 
-    pub fn filter_by(mut self, filter: PostingIterator<'a>) -> Self {
-        match filter {
-            PostingIterator::Decoder(decoder) => {
-                self.filter.push(PeekableSeekable::new(Operand::Term(Weight(1.0),
-                                                                     decoder,
-                                                                     "filter term".to_string(),
-                                                                     "filter field".to_string())))
-            }
-            _ => {}
+    pub fn with_capabilities(mut self, capabilities: &[&str]) -> Self {
+        self.capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// Multiplies the weights of matches in `field` by `boost`.
+    /// Fields without a boost keep a factor of 1.
+    pub fn boost(mut self, field: &str, boost: f32) -> Self {
+        self.boosts.insert(field.to_string(), boost);
+        self
+    }
+
+    /// The boost of `field`. 1 if none is set
+    pub fn field_boost(&self, field: &str) -> f32 {
+        self.boosts.get(field).cloned().unwrap_or(1.)
+    }
+
+    /// If set, every matching document is yielded instead of stopping at
+    /// low weight operand combinations. See `WeightingOptions`.
+    pub fn exhaustive(mut self, exhaustive: bool) -> Self {
+        self.exhaustive = exhaustive;
+        self
+    }
+
+    /// Stops the query after `limit` results
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Declares that the query pipeline folds case with `mode` (see
+    /// `CaseFolder`). Running the query on an index recorded with another
+    /// mode is an error: `try_run_query` fails with
+    /// `QueryError::CaseFoldingMismatch` and `run_query` panics.
+    pub fn case_folding(mut self, mode: CaseFolding) -> Self {
+        self.case_folding = Some(mode);
+        self
+    }
+
+    /// If set, only one document per external id is yielded.
+    /// Which one is decided by the `DedupePolicy`
+    pub fn dedupe_by_external_id(mut self, dedupe: bool) -> Self {
+        self.dedupe_by_external_id = dedupe;
+        self
+    }
+
+    pub fn dedupe_policy(mut self, policy: DedupePolicy) -> Self {
+        self.dedupe_policy = policy;
+        self
+    }
+
+    /// Returns the policy to deduplicate with, if deduplication is enabled
+    pub fn dedupe(&self) -> Option<DedupePolicy> {
+        if self.dedupe_by_external_id {
+            Some(self.dedupe_policy)
+        } else {
+            None
         }
+    }
+
+    pub fn weighting_options(&self) -> WeightingOptions {
+        let mut options = if self.exhaustive {
+            WeightingOptions::exhaustive()
+        } else {
+            WeightingOptions::default()
+        };
+        options.limit = self.limit;
+        options
+    }
+
+    /// Filters by an operand, e.g. a range from `Field::range`.
+    ///
+    /// If the query lacks the capability for the operand's field, the query
+    /// matches nothing. See `Field::required_capability`.
+    pub fn filter_by_operand(mut self, filter: Operand<'a>) -> Self {
+        self.filter.push(PeekableSeekable::new(filter));
         self
     }
+
+    /// Filters by the documents containing `term` in `field`.
+    /// If `term` is unknown, nothing matches.
+    pub fn filter_by<T>(self, field: &'a Field<T>, term: &T) -> Self
+        where T: Hash + Eq + Ord + Debug + ToString
+    {
+        let operand = match field.query_term(term) {
+            Some((_, decoder)) => {
+                Operand::Term(Weight(1.0), decoder, term.to_string(), field.name().to_string())
+            }
+            None => {
+                Operand::Any(Weight(1.0),
+                             MinShouldMatch::create(Vec::new(), 1),
+                             term.to_string(),
+                             field.name().to_string())
+            }
+        };
+        self.filter_by_operand(operand)
+    }
+}
+
+/// Returned by `try_run_query` on the derived index
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// A clause or filter targets this field, which requires a capability
+    /// the query was not granted
+    RestrictedField(String),
+    /// The query folds case differently than the index.
+    /// See `Query::case_folding`
+    CaseFoldingMismatch(CaseFoldingMismatch),
 }
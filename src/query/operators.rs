@@ -1,13 +1,15 @@
+use std::cmp;
 use std::hash::Hash;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::collections::HashMap;
 
-use perlin_core::index::posting::{Posting, PostingIterator, PostingDecoder};
+use perlin_core::index::posting::{Posting, PostingDecoder};
 use perlin_core::utils::seeking_iterator::{PeekableSeekable, SeekingIterator};
 use perlin_core::utils::progress::Progress;
 
 use language::CanApply;
-use query::{Weight, ToOperands, Operand};
+use query::{Weight, ToOperands, Operand, Query};
 use field::{Field, Fields};
 
 #[derive(Debug)]
@@ -19,21 +21,48 @@ pub enum Combinator {
 /// This funnel is used at an end of a query pipeline
 /// It calls `index.query_atom` and stores the result, which is lazy
 /// When `to_operand` is then called, it packs everything into an operator!
+///
+/// Fields that require a capability not in `capabilities` are skipped.
+/// Clauses, filters and the operands returned by `Field::range`,
+/// `Field::descendants` and `Field::query_prefix` check the capability
+/// themselves.
+///
+/// Weights of operands are multiplied with the boost of their field.
 pub struct Funnel<'a, T: 'a, TIndex: 'a> {
     index: &'a TIndex,
+    capabilities: Vec<String>,
+    boosts: HashMap<String, f32>,
     result: Vec<PeekableSeekable<Operand<'a>>>,
     _term: PhantomData<T>,
 }
 
 impl<'a, T: 'a, TIndex: 'a> Funnel<'a, T, TIndex> {
+    /// Creates a funnel without any capabilities
     pub fn create(index: &'a TIndex) -> Self {
+        Self::create_as(index, &[])
+    }
+
+    pub fn create_as(index: &'a TIndex, capabilities: &[String]) -> Self {
         Funnel {
             index,
+            capabilities: capabilities.to_vec(),
+            boosts: HashMap::new(),
             result: Vec::new(),
             _term: PhantomData,
         }
     }
 
+    /// Creates a funnel with the capabilities and field boosts of `query`
+    pub fn for_query(index: &'a TIndex, query: &Query) -> Self {
+        let mut funnel = Self::create_as(index, &query.capabilities);
+        funnel.boosts = query.boosts.clone();
+        funnel
+    }
+
+    fn boost(&self, field: &str) -> f32 {
+        self.boosts.get(field).cloned().unwrap_or(1.)
+    }
+
     fn add_posting_list(&mut self,
                         weight: Weight,
                         decoder: PostingDecoder<'a>,
@@ -50,16 +79,17 @@ impl<'a: 'b, 'b, T: 'a + Hash + Eq + Ord + Debug + ToString> CanApply<&'b T>
     type Output = T;
 
     fn apply(&mut self, term: &'b T) {
-        for (key, index) in self.index.fields.iter() {
-            let w = 1./index.term_doc_ratio;
-            match index.query_atom(&term) {
-                (idf, PostingIterator::Decoder(decoder)) => {
-                    self.add_posting_list(Weight(idf.0 * w),
-                                          decoder,
-                                          term.to_string(),
-                                          key.clone());
-                }
-                _ => {}
+        // Fields iterate in declaration order, so operands do too
+        for index in self.index.iter() {
+            if !index.is_visible_to(&self.capabilities) {
+                continue;
+            }
+            let w = self.boost(index.name()) / index.term_doc_ratio;
+            if let Some((idf, decoder)) = index.query_term(&term) {
+                self.add_posting_list(Weight(idf * w),
+                                      decoder,
+                                      term.to_string(),
+                                      index.name().to_string());
             }
         }
     }
@@ -70,16 +100,17 @@ impl<'a, T: 'a + Hash + Eq + Ord + Debug + ToString> CanApply<T> for Funnel<'a,
     type Output = T;
 
     fn apply(&mut self, term: T) {
-        for (key, index) in self.index.fields.iter() {
-            let w = 1./index.term_doc_ratio;
-            match index.query_atom(&term) {
-                (idf, PostingIterator::Decoder(decoder)) => {
-                    self.add_posting_list(Weight(idf.0 * w),
-                                          decoder,
-                                          term.to_string(),
-                                          key.clone());
-                }
-                _ => {}
+        // Fields iterate in declaration order, so operands do too
+        for index in self.index.iter() {
+            if !index.is_visible_to(&self.capabilities) {
+                continue;
+            }
+            let w = self.boost(index.name()) / index.term_doc_ratio;
+            if let Some((idf, decoder)) = index.query_term(&term) {
+                self.add_posting_list(Weight(idf * w),
+                                      decoder,
+                                      term.to_string(),
+                                      index.name().to_string());
             }
         }
     }
@@ -91,15 +122,15 @@ impl<'a: 'b, 'b, T: 'a + Hash + Eq + Ord + Debug + ToString> CanApply<&'b T>
     type Output = T;
 
     fn apply(&mut self, term: &'b T) {
-        let w = 1./self.index.term_doc_ratio;
-        match self.index.query_atom(&term) {
-            (idf, PostingIterator::Decoder(decoder)) => {
-                self.add_posting_list(Weight(idf.0 * w),
-                                      decoder,
-                                      term.to_string(),
-                                      self.index.name.clone());
-            }
-            _ => {}
+        if !self.index.is_visible_to(&self.capabilities) {
+            return;
+        }
+        let w = self.boost(self.index.name()) / self.index.term_doc_ratio;
+        if let Some((idf, decoder)) = self.index.query_term(&term) {
+            self.add_posting_list(Weight(idf * w),
+                                  decoder,
+                                  term.to_string(),
+                                  self.index.name().to_string());
         }
     }
 }
@@ -109,15 +140,15 @@ impl<'a, T: 'a + Hash + Eq + Ord + Debug + ToString> CanApply<T> for Funnel<'a,
     type Output = T;
 
     fn apply(&mut self, term: T) {
-        let w = 1./self.index.term_doc_ratio;
-        match self.index.query_atom(&term) {
-            (idf, PostingIterator::Decoder(decoder)) => {
-                self.add_posting_list(Weight(idf.0 * w),
-                                      decoder,
-                                      term.to_string(),
-                                      self.index.name.clone());
-            }
-            _ => {}
+        if !self.index.is_visible_to(&self.capabilities) {
+            return;
+        }
+        let w = self.boost(self.index.name()) / self.index.term_doc_ratio;
+        if let Some((idf, decoder)) = self.index.query_term(&term) {
+            self.add_posting_list(Weight(idf * w),
+                                  decoder,
+                                  term.to_string(),
+                                  self.index.name().to_string());
         }
     }
 }
@@ -181,3 +212,209 @@ impl And {
             .unwrap_or(Progress::done())
     }
 }
+
+/// Yields every posting that at least `min` of the operands agree on.
+///
+/// `min == 1` behaves like an OR of all operands, `min == operands.len()` like
+/// `And`. If `min` is larger than the number of operands nothing is yielded.
+/// `min == 0` would match every document and is treated like `min == 1`.
+#[derive(Clone)]
+pub struct MinShouldMatch<'a> {
+    min: usize,
+    heads: Vec<Option<Posting>>,
+    operands: Vec<PeekableSeekable<Operand<'a>>>,
+}
+
+impl<'a> MinShouldMatch<'a> {
+    pub fn create(mut operands: Vec<PeekableSeekable<Operand<'a>>>, min: usize) -> Self {
+        let min = cmp::max(min, 1);
+        let heads = operands.iter_mut().map(|op| op.next()).collect();
+        MinShouldMatch {
+            min,
+            heads,
+            operands,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.operands
+            .iter()
+            .map(|op| op.inner().progress())
+            .min()
+            .unwrap_or(Progress::done())
+    }
+
+    pub fn next_seek(&mut self, target: &Posting) -> Option<Posting> {
+        for (head, op) in self.heads.iter_mut().zip(self.operands.iter_mut()) {
+            let behind = match *head {
+                Some(ref posting) => posting.0 < target.0,
+                None => false,
+            };
+            if behind {
+                *head = op.next_seek(target);
+            }
+        }
+        self.next()
+    }
+}
+
+impl<'a> Iterator for MinShouldMatch<'a> {
+    type Item = Posting;
+
+    fn next(&mut self) -> Option<Posting> {
+        if self.min > self.operands.len() {
+            return None;
+        }
+        loop {
+            // The smallest posting any operand is positioned on is the next candidate
+            let candidate = self.heads.iter().filter_map(|head| head.clone()).min()?;
+            // Count the operands positioned on it and advance them
+            let mut count = 0;
+            for (head, op) in self.heads.iter_mut().zip(self.operands.iter_mut()) {
+                if head.as_ref() == Some(&candidate) {
+                    count += 1;
+                    *head = op.next();
+                }
+            }
+            if count >= self.min {
+                return Some(candidate);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::{Posting, DocId};
+
+    use field::Fields;
+    use language::{CanApply, PipelineBucket};
+    use query::{ToOperands, Funnel};
+    use test_utils::create_fields;
+
+    use super::{And, MinShouldMatch};
+
+    fn min_should_match(fields: &Fields<String>, min: usize) -> Vec<u32> {
+        let mut funnel = Funnel::create(fields);
+        for term in &["deimos", "phobos", "ceres"] {
+            funnel.apply(term.to_string());
+        }
+        MinShouldMatch::create(funnel.to_operands(), min)
+            .map(|Posting(DocId(id))| id)
+            .collect()
+    }
+
+    #[test]
+    fn min_should_match_counts() {
+        let mut fields = create_fields("query/min_should_match", &["body"]);
+        let docs: &[&[&str]] = &[&["deimos", "phobos", "ceres"],
+                                 &["deimos", "phobos"],
+                                 &["deimos"],
+                                 &["phobos", "ceres"],
+                                 &["ceres"],
+                                 &["vesta"]];
+        for (doc_id, doc) in docs.iter().enumerate() {
+            for term in doc.iter() {
+                fields.get_mut("body").unwrap().put(DocId(doc_id as u32), term.to_string());
+            }
+        }
+        fields.commit();
+
+        assert_eq!(min_should_match(&fields, 1), vec![0, 1, 2, 3, 4]);
+        assert_eq!(min_should_match(&fields, 2), vec![0, 1, 3]);
+        assert_eq!(min_should_match(&fields, 3), vec![0]);
+        assert_eq!(min_should_match(&fields, 4), Vec::<u32>::new());
+
+        // n == operands.len() behaves like And
+        let mut funnel = Funnel::create(&fields);
+        for term in &["deimos", "phobos", "ceres"] {
+            funnel.apply(term.to_string());
+        }
+        let mut operands = funnel.to_operands();
+        let mut and = Vec::new();
+        while let Some(Posting(DocId(id))) = And::next(&mut operands) {
+            and.push(id);
+        }
+        assert_eq!(min_should_match(&fields, 3), and);
+    }
+
+    #[test]
+    fn min_should_match_zero() {
+        let mut fields = create_fields("query/min_should_match_zero", &["body"]);
+        let docs: &[&[&str]] = &[&["deimos"], &["vesta"], &["phobos", "ceres"]];
+        for (doc_id, doc) in docs.iter().enumerate() {
+            for term in doc.iter() {
+                fields.get_mut("body").unwrap().put(DocId(doc_id as u32), term.to_string());
+            }
+        }
+        fields.commit();
+
+        // Matches any operand instead of every document
+        assert_eq!(min_should_match(&fields, 0), vec![0, 2]);
+        assert_eq!(min_should_match(&fields, 0), min_should_match(&fields, 1));
+    }
+
+    #[test]
+    fn field_boosts() {
+        use query::{Query, QueryBuilder, WeightingOperator, resolve_clause};
+
+        let mut fields = create_fields("query/field_boosts", &["title", "body"]);
+        fields.get_mut("title").unwrap().put(DocId(0), "ceres".to_string());
+        fields.get_mut("body").unwrap().put(DocId(0), "vesta".to_string());
+        fields.get_mut("title").unwrap().put(DocId(1), "vesta".to_string());
+        fields.get_mut("body").unwrap().put(DocId(1), "ceres".to_string());
+        fields.commit();
+
+        let run = |query: Query| {
+            let mut funnel = Funnel::for_query(&fields, &query);
+            funnel.apply("ceres".to_string());
+            WeightingOperator::create(funnel.to_operands(), vec![])
+                .map(|Posting(DocId(id))| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(Query::new("ceres").boost("title", 3.)), vec![0, 1]);
+        assert_eq!(run(Query::new("ceres").boost("body", 3.)), vec![1, 0]);
+
+        // Clauses are boosted as well
+        let clause_weight = |query: &Query| {
+            resolve_clause(fields.get("body").unwrap(), &query.clauses[0], query)
+                .unwrap()
+                .inner()
+                .weight()
+                .0
+        };
+        let clause = QueryBuilder::new("").may("body", "ceres").build();
+        let boosted = clause_weight(&clause.clone().boost("body", 3.));
+        assert!((boosted - 3. * clause_weight(&clause)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weighting_limit() {
+        use perlin_core::utils::progress::Progress;
+        use query::{WeightingOperator, WeightingOptions};
+
+        let mut fields = create_fields("query/weighting_limit", &["title", "body"]);
+        for doc_id in 0..10 {
+            fields.get_mut("body").unwrap().put(DocId(doc_id), "ceres".to_string());
+            if doc_id % 3 == 0 {
+                fields.get_mut("title").unwrap().put(DocId(doc_id), "ceres".to_string());
+            }
+        }
+        fields.commit();
+
+        let operator = |limit| {
+            let mut funnel = Funnel::create(&fields);
+            funnel.apply("ceres".to_string());
+            let options = WeightingOptions { limit: limit, ..WeightingOptions::default() };
+            WeightingOperator::create_with_options(funnel.to_operands(), vec![], options)
+        };
+        let all = operator(None).collect::<Vec<_>>();
+        assert_eq!(all.len(), 10);
+        let mut limited = operator(Some(4));
+        assert_eq!(limited.by_ref().collect::<Vec<_>>(), &all[..4]);
+        assert!(limited.progress() == Progress::done());
+        assert_eq!(limited.next(), None);
+        assert!(operator(Some(0)).next().is_none());
+        assert_eq!(operator(Some(20)).collect::<Vec<_>>(), all);
+    }
+}
@@ -0,0 +1,308 @@
+use query::{ChainingOperator, QueryBuilder};
+
+/// Returned by `QueryBuilder::parse`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset into the input
+    pub position: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Term(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+// Groups carry the byte offset they start at, for errors
+#[derive(Debug)]
+enum Expr {
+    Term(String),
+    Not(usize, Box<Expr>),
+    And(usize, Vec<Expr>),
+    Or(usize, Vec<Expr>),
+}
+
+impl<'a> QueryBuilder<'a> {
+    /// Parses `input` into clauses on `field`.
+    ///
+    /// `AND`, `OR` and `NOT` are case insensitive. `AND` binds tighter than
+    /// `OR` and is implied between terms. Parentheses group.
+    /// Quoted phrases are rejected, as fields do not index positions.
+    ///
+    /// Terms combined by `AND` become must clauses and negated terms must not
+    /// clauses. Terms combined by `OR` become a single clause matching any of
+    /// them, so `foo AND (bar OR baz)` yields the documents containing "foo"
+    /// and "bar" or "baz". Groups that clauses cannot express are rejected:
+    /// `AND` or `NOT` inside `OR` (e.g. `foo bar OR baz`) and negated `AND`
+    /// groups (e.g. `NOT (foo AND bar)`).
+    ///
+    /// Like all clause terms, terms have to be given as they were indexed.
+    pub fn parse(field: &str, input: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(input)?;
+        let builder = QueryBuilder::new("");
+        if tokens.is_empty() {
+            return Ok(builder);
+        }
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            end: input.len(),
+        };
+        let expr = parser.or()?;
+        if let Some(&(position, _)) = parser.peek() {
+            return Err(ParseError {
+                position,
+                reason: "unbalanced closing parenthesis".to_string(),
+            });
+        }
+        builder.add_expr(field, expr, ChainingOperator::Must)
+    }
+
+    // `operator` is either `Must` or `MustNot`
+    fn add_expr(self,
+                field: &str,
+                expr: Expr,
+                operator: ChainingOperator)
+                -> Result<Self, ParseError> {
+        match expr {
+            Expr::Term(_) | Expr::Or(_, _) => {
+                let mut alternatives = Vec::new();
+                collect_alternatives(expr, &mut alternatives)?;
+                let alternatives = alternatives.iter().map(|term| term.as_str()).collect::<Vec<_>>();
+                Ok(self.any_as(operator, field, &alternatives))
+            }
+            Expr::Not(_, inner) => {
+                let negated = match operator {
+                    ChainingOperator::MustNot => ChainingOperator::Must,
+                    _ => ChainingOperator::MustNot,
+                };
+                self.add_expr(field, *inner, negated)
+            }
+            Expr::And(position, exprs) => {
+                if operator == ChainingOperator::MustNot {
+                    return Err(ParseError {
+                        position,
+                        reason: "negated AND groups are not supported".to_string(),
+                    });
+                }
+                exprs.into_iter().fold(Ok(self), |builder, expr| {
+                    builder.and_then(|builder| builder.add_expr(field, expr, operator))
+                })
+            }
+        }
+    }
+}
+
+/// Collects the terms combined by `OR` in `expr`
+fn collect_alternatives(expr: Expr, alternatives: &mut Vec<String>) -> Result<(), ParseError> {
+    match expr {
+        Expr::Term(term) => alternatives.push(term),
+        Expr::Or(_, exprs) => {
+            for expr in exprs {
+                collect_alternatives(expr, alternatives)?;
+            }
+        }
+        Expr::And(position, _) => {
+            return Err(ParseError {
+                position,
+                reason: "AND inside OR is not supported".to_string(),
+            })
+        }
+        Expr::Not(position, _) => {
+            return Err(ParseError {
+                position,
+                reason: "NOT inside OR is not supported".to_string(),
+            })
+        }
+    }
+    Ok(())
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push((start, Token::Open));
+        } else if c == ')' {
+            chars.next();
+            tokens.push((start, Token::Close));
+        } else if c == '"' {
+            return Err(ParseError {
+                position: start,
+                reason: "phrases are not supported".to_string(),
+            });
+        } else {
+            let mut word = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            let token = match word.to_lowercase().as_str() {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Term(word),
+            };
+            tokens.push((start, token));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'t> {
+    tokens: &'t [(usize, Token)],
+    pos: usize,
+    // Position reported for errors at the end of the input
+    end: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn peek(&self) -> Option<&'t (usize, Token)> {
+        self.tokens.get(self.pos)
+    }
+
+    // Offset of the next token
+    fn position(&self) -> usize {
+        self.peek().map(|&(position, _)| position).unwrap_or(self.end)
+    }
+
+    fn error<T>(&self, reason: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            position: self.position(),
+            reason: reason.to_string(),
+        })
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position();
+        let mut exprs = vec![self.and()?];
+        while let Some(&(_, Token::Or)) = self.peek() {
+            self.pos += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::Or(start, exprs) })
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let start = self.position();
+        let mut exprs = vec![self.unary()?];
+        loop {
+            match self.peek() {
+                Some(&(_, Token::And)) => {
+                    self.pos += 1;
+                    exprs.push(self.unary()?);
+                }
+                Some(&(_, Token::Or)) |
+                Some(&(_, Token::Close)) |
+                None => break,
+                // AND is implied
+                Some(_) => exprs.push(self.unary()?),
+            }
+        }
+        Ok(if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::And(start, exprs) })
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if let Some(&(start, Token::Not)) = self.peek() {
+            self.pos += 1;
+            return Ok(Expr::Not(start, Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some(&(_, Token::Term(ref term))) => {
+                self.pos += 1;
+                Ok(Expr::Term(term.clone()))
+            }
+            Some(&(open, Token::Open)) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                match self.peek() {
+                    Some(&(_, Token::Close)) => {
+                        self.pos += 1;
+                        Ok(expr)
+                    }
+                    _ => {
+                        Err(ParseError {
+                            position: open,
+                            reason: "unbalanced opening parenthesis".to_string(),
+                        })
+                    }
+                }
+            }
+            Some(&(_, Token::Close)) => self.error("unbalanced closing parenthesis"),
+            Some(_) => self.error("expected a term"),
+            None => self.error("unexpected end of query"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use query::{ChainingOperator, QueryBuilder};
+    use super::ParseError;
+
+    fn clauses(input: &str) -> Vec<(ChainingOperator, Vec<String>)> {
+        QueryBuilder::parse("body", input)
+            .unwrap()
+            .build()
+            .clauses
+            .into_iter()
+            .map(|clause| (clause.operator, clause.terms))
+            .collect()
+    }
+
+    fn terms(terms: &[&str]) -> Vec<String> {
+        terms.iter().map(|term| term.to_string()).collect()
+    }
+
+    fn error(input: &str) -> (usize, String) {
+        let ParseError { position, reason } = QueryBuilder::parse("body", input).err().unwrap();
+        (position, reason)
+    }
+
+    #[test]
+    fn parse() {
+        use query::ChainingOperator::*;
+
+        assert_eq!(clauses("foo AND (bar or baz) NOT qux"),
+                   vec![(Must, terms(&["foo"])),
+                        (Must, terms(&["bar", "baz"])),
+                        (MustNot, terms(&["qux"]))]);
+        assert_eq!(clauses("foo OR (bar OR baz)"),
+                   vec![(Must, terms(&["foo", "bar", "baz"]))]);
+        assert_eq!(clauses("not (foo Or bar)"), vec![(MustNot, terms(&["foo", "bar"]))]);
+        assert_eq!(clauses("foo NOT NOT bar"),
+                   vec![(Must, terms(&["foo"])), (Must, terms(&["bar"]))]);
+        assert!(clauses("  ").is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("foo AND (bar OR baz"),
+                   (8, "unbalanced opening parenthesis".to_string()));
+        assert_eq!(error("foo) bar"), (3, "unbalanced closing parenthesis".to_string()));
+        assert_eq!(error("foo AND"), (7, "unexpected end of query".to_string()));
+        assert_eq!(error("foo OR OR bar"), (7, "expected a term".to_string()));
+        assert_eq!(error("say \"hello world\""), (4, "phrases are not supported".to_string()));
+        assert_eq!(error("()"), (1, "unbalanced closing parenthesis".to_string()));
+        assert_eq!(error("foo bar OR baz"), (0, "AND inside OR is not supported".to_string()));
+        assert_eq!(error("foo OR NOT bar"), (7, "NOT inside OR is not supported".to_string()));
+        assert_eq!(error("NOT (foo AND bar)"),
+                   (5, "negated AND groups are not supported".to_string()));
+    }
+}
@@ -1,50 +1,50 @@
 #[macro_export]
 macro_rules! operand {
-    (;$INDEX:ident; [$operator:ident in $this_field:ident]) => {
-        Funnel::create(&$INDEX.$this_field)
+    (;$INDEX:ident; ;$QUERY:ident; [$operator:ident in $this_field:ident]) => {
+        Funnel::for_query(&$INDEX.$this_field, &$QUERY)
     };
 }
 #[macro_export]
 macro_rules! inner_query_pipe {
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      > $($x:tt)*) => {
         // >
-        inner_query_pipe!(;$INDEX; $($x)*)
+        inner_query_pipe!(;$INDEX; ;$QUERY; $($x)*)
     };
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      $element:ident($($param:expr),+)
      | [$operator:ident in $this_field:ident] $($x:tt)*) =>
     // Element(params) | [OP in field]
     {
         $element::create($($param),+ ,
-                         operand!(;$INDEX; [$operator in $this_field]),
-                         inner_query_pipe!(;$INDEX; $($x)*))
+                         operand!(;$INDEX; ;$QUERY; [$operator in $this_field]),
+                         inner_query_pipe!(;$INDEX; ;$QUERY; $($x)*))
     };
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      $element:ident($($param:expr),+) $($x:tt)*) =>
     // Element(params)
     {
-        $element::create($($param),+ , inner_query_pipe!(;$INDEX; $($x)*))
+        $element::create($($param),+ , inner_query_pipe!(;$INDEX; ;$QUERY; $($x)*))
     };
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      $element:ident
      | [$operator:ident in $this_field:ident] $($x:tt)*) =>
     // Element | [OP in field]
     {
         $element::create(
-            operand!(;$INDEX; [$operator in $this_field]),
-            inner_query_pipe!(;$INDEX; $($x)*))
+            operand!(;$INDEX; ;$QUERY; [$operator in $this_field]),
+            inner_query_pipe!(;$INDEX; ;$QUERY; $($x)*))
     };
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      [$operator:ident in $this_field:ident]) => {
         // [All in field]
-        Funnel::create(&$INDEX.$this_field)
+        Funnel::for_query(&$INDEX.$this_field, &$QUERY)
     };
-    (;$INDEX:ident;
+    (;$INDEX:ident; ;$QUERY:ident;
      $element:ident $($x:tt)*) =>
     // Element
     {
-        $element::create(inner_query_pipe!(;$INDEX; $($x)*))
+        $element::create(inner_query_pipe!(;$INDEX; ;$QUERY; $($x)*))
     };
     () => {}
 }
@@ -58,7 +58,7 @@ macro_rules! query_pipeline {
             use perlin_core::utils::seeking_iterator::PeekableSeekable;
 
             // Build the pipeline
-            let mut pipeline = inner_query_pipe!(;index; $($x)*);
+            let mut pipeline = inner_query_pipe!(;index; ;query; $($x)*);
             // Run the query-string through it
             pipeline.apply(&query.query);
             // And retrieve all operands
@@ -0,0 +1,124 @@
+use std::vec;
+use std::collections::BTreeMap;
+
+use perlin_core::index::posting::{Posting, DocId};
+use perlin_core::utils::seeking_iterator::PeekableSeekable;
+
+use query::{Operand, Weight};
+use utils::TopKCollector;
+
+/// BM25 parameters
+#[derive(Debug, Copy, Clone)]
+pub struct Bm25 {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Default for Bm25 {
+    fn default() -> Self {
+        Bm25 { k1: 1.2, b: 0.75 }
+    }
+}
+
+impl Bm25 {
+    /// Score of a single matching term.
+    /// `relative_length` is the document's length in the field divided by the
+    /// average length in the field.
+    pub fn term_score(&self, idf: f32, tf: f32, relative_length: f32) -> f32 {
+        idf * (tf * (self.k1 + 1.)) / (tf + self.k1 * (1. - self.b + self.b * relative_length))
+    }
+}
+
+/// Yields the top-k documents by BM25 score, best first, ties by ascending DocId.
+///
+/// Every document matching any operand and all filters, but none of the
+/// excluded operands, is a candidate.
+/// The weight of an operand (idf times field boost) is used as the idf part
+/// of BM25. Postings carry no term frequency, so every match counts with a
+/// term frequency of 1.
+///
+/// Unlike `WeightingOperator`, this decodes all operands completely before
+/// yielding anything.
+pub struct ScoredOperator {
+    results: vec::IntoIter<(Posting, f32)>,
+}
+
+impl ScoredOperator {
+    /// `relative_length(field, doc_id)` returns the document's length in the
+    /// field relative to the field's average length. 1.0 if unknown.
+    pub fn create<'a, F>(operands: Vec<PeekableSeekable<Operand<'a>>>,
+                         filters: Vec<PeekableSeekable<Operand<'a>>>,
+                         excluded: Vec<PeekableSeekable<Operand<'a>>>,
+                         k: usize,
+                         params: Bm25,
+                         relative_length: F)
+                         -> Self
+        where F: Fn(&str, DocId) -> f32
+    {
+        debug!(target: "perlin::query",
+               "Scoring {} operands with {} filters for the top {}",
+               operands.len(),
+               filters.len(),
+               k);
+        let mut scores = BTreeMap::new();
+        for operand in operands {
+            let operand = operand.inner().clone();
+            let (weight, field) = (operand.weight(), operand.field().to_string());
+            for Posting(doc_id) in operand {
+                let score = params.term_score(weight.0, 1., relative_length(&field, doc_id));
+                *scores.entry(doc_id).or_insert(0.) += score;
+            }
+        }
+
+        let filters = filters.into_iter()
+            .map(|filter| filter.inner().clone().map(|Posting(doc_id)| doc_id).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut excluded = excluded.into_iter()
+            .flat_map(|operand| operand.inner().clone().map(|Posting(doc_id)| doc_id))
+            .collect::<Vec<_>>();
+        excluded.sort();
+        excluded.dedup();
+
+        let mut collector = TopKCollector::new(k, 0, |&(doc_id, score): &(DocId, f32)| {
+            (Weight(score), doc_id)
+        });
+        for (doc_id, score) in scores {
+            if filters.iter().all(|filter| filter.binary_search(&doc_id).is_ok()) &&
+               excluded.binary_search(&doc_id).is_err() {
+                collector.push((doc_id, score));
+            }
+        }
+        ScoredOperator {
+            results: collector.into_sorted_vec()
+                .into_iter()
+                .map(|(doc_id, score)| (Posting(doc_id), score))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        }
+    }
+}
+
+impl Iterator for ScoredOperator {
+    type Item = (Posting, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.results.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bm25;
+
+    #[test]
+    fn term_score() {
+        let bm25 = Bm25::default();
+        // At average length the score only depends on tf
+        assert!((bm25.term_score(2., 1., 1.) - 2.).abs() < 1e-6);
+        // Shorter documents score higher
+        assert!(bm25.term_score(2., 1., 0.5) > bm25.term_score(2., 1., 2.));
+        // No length normalization with b = 0
+        let no_norm = Bm25 { k1: 1.2, b: 0. };
+        assert_eq!(no_norm.term_score(2., 1., 0.5), no_norm.term_score(2., 1., 2.));
+    }
+}
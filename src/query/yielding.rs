@@ -0,0 +1,91 @@
+/// Wraps a result iterator and calls a hook every `every` results.
+///
+/// Driving a heavy query directly inside an async task can starve the
+/// executor. The hook is a plain `FnMut()` so this stays runtime-agnostic:
+/// async callers pass a closure that yields to their runtime, sync callers
+/// simply use no hook at all.
+pub struct YieldingResults<I, F> {
+    inner: I,
+    every: usize,
+    counter: usize,
+    hook: Option<F>,
+}
+
+impl<I, F> YieldingResults<I, F>
+    where I: Iterator,
+          F: FnMut()
+{
+    /// Calls `hook` after every `every` results. An `every` of 0 is treated as 1.
+    pub fn new(inner: I, every: usize, hook: Option<F>) -> Self {
+        YieldingResults {
+            inner: inner,
+            every: if every == 0 { 1 } else { every },
+            counter: 0,
+            hook: hook,
+        }
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+}
+
+impl<I, F> Iterator for YieldingResults<I, F>
+    where I: Iterator,
+          F: FnMut()
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.inner.next();
+        if next.is_some() {
+            if let Some(ref mut hook) = self.hook {
+                self.counter += 1;
+                if self.counter == self.every {
+                    self.counter = 0;
+                    hook();
+                }
+            }
+        }
+        next
+    }
+}
+
+/// Collects the first `k` results of `iter` calling `hook` every `every` results
+pub fn collect_top_yielding<I, F>(iter: I, k: usize, every: usize, hook: F) -> Vec<I::Item>
+    where I: Iterator,
+          F: FnMut()
+{
+    YieldingResults::new(iter, every, Some(hook)).take(k).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cadence() {
+        let mut calls = 0;
+        {
+            let results = YieldingResults::new(0..10_000, 100, Some(|| calls += 1));
+            assert_eq!(results.count(), 10_000);
+        }
+        assert_eq!(calls, 100);
+    }
+
+    #[test]
+    fn identical_results() {
+        let with_hook = YieldingResults::new(0..1000, 7, Some(|| {})).collect::<Vec<_>>();
+        let without_hook = YieldingResults::new(0..1000, 7, None::<fn()>).collect::<Vec<_>>();
+        assert_eq!(with_hook, (0..1000).collect::<Vec<_>>());
+        assert_eq!(with_hook, without_hook);
+    }
+
+    #[test]
+    fn top_k() {
+        let mut calls = 0;
+        assert_eq!(collect_top_yielding(0..1000, 10, 3, || calls += 1),
+                   (0..10).collect::<Vec<_>>());
+        assert_eq!(calls, 3);
+    }
+}
@@ -1,6 +1,14 @@
 use std::fs;
 use std::env::temp_dir;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::{Once, ONCE_INIT};
+
+use log::{self, Log, Level, LevelFilter, Metadata, Record};
+
+use field::{Fields, FieldSupplement};
+use language::CanApply;
 
 const TEST_FOLDER: &'static str = "perlin_tests";
 
@@ -9,8 +17,108 @@ pub fn test_dir() -> PathBuf{
     temp_dir().join(TEST_FOLDER)
 }
 
+/// Creates an empty folder for a test. Leftovers of earlier runs are removed.
 pub fn create_test_dir(dir: &str) -> PathBuf {
     let path = test_dir().join(dir);
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
     fs::create_dir_all(&path).unwrap();
     path
 }
+
+/// Creates `Fields` with `names` in the test folder `dir`
+pub fn create_fields(dir: &str, names: &[&str]) -> Fields<String> {
+    let path = create_test_dir(dir);
+    let mut fields = Fields::new();
+    for name in names {
+        fields.add_field(name.to_string(), &path, FieldSupplement::None).unwrap();
+    }
+    fields
+}
+
+/// Ends a pipeline in tests of single elements by collecting its terms.
+/// Clones share the collected terms.
+#[derive(Clone)]
+pub struct Collector(Rc<RefCell<Vec<String>>>);
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector(Rc::new(RefCell::new(Vec::new())))
+    }
+
+    /// Returns the terms collected so far
+    pub fn terms(&self) -> Vec<String> {
+        self.0.borrow().clone()
+    }
+}
+
+impl CanApply<String> for Collector {
+    type Output = String;
+    fn apply(&mut self, input: String) {
+        self.0.borrow_mut().push(input);
+    }
+}
+
+impl<'a> CanApply<&'a str> for Collector {
+    type Output = String;
+    fn apply(&mut self, input: &'a str) {
+        self.0.borrow_mut().push(input.to_string());
+    }
+}
+
+thread_local!(static RECORDS: RefCell<Vec<(String, Level, String)>> = RefCell::new(Vec::new()));
+
+/// Records perlin's log records per thread, so parallel tests don't interfere
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("perlin")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            RECORDS.with(|records| {
+                records.borrow_mut().push((record.target().to_string(),
+                                           record.level(),
+                                           record.args().to_string()))
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+static INIT_LOGGER: Once = ONCE_INIT;
+
+/// Runs `f` and returns the (target, level, message) of every record it logged
+pub fn capture_logs<F: FnOnce()>(f: F) -> Vec<(String, Level, String)> {
+    INIT_LOGGER.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+    RECORDS.with(|records| records.borrow_mut().clear());
+    f();
+    RECORDS.with(|records| records.borrow_mut().drain(..).collect())
+}
+
+thread_local!(static EVENTS: RefCell<Vec<String>> = RefCell::new(Vec::new()));
+
+/// Called by `count_event!`
+pub fn record_event(event: String) {
+    EVENTS.with(|events| events.borrow_mut().push(event));
+}
+
+/// Runs `f` and returns the events it recorded with `count_event!` in order
+pub fn events<F: FnOnce()>(f: F) -> Vec<String> {
+    EVENTS.with(|events| events.borrow_mut().clear());
+    f();
+    EVENTS.with(|events| events.borrow_mut().drain(..).collect())
+}
+
+/// Runs `f` and returns how often it recorded `event`
+pub fn count_events<F: FnOnce()>(event: &str, f: F) -> usize {
+    events(f).iter().filter(|e| *e == event).count()
+}
@@ -1,6 +1,277 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::collections::BinaryHeap;
+use std::collections::hash_map::DefaultHasher;
+
+use perlin_core::index::posting::DocId;
+
+use memory::{MemoryReport, MemoryUsage};
+
+/// Records an event for `test_utils::events`, formatted like `format!`.
+/// Expands to nothing outside of tests.
+macro_rules! count_event {
+    ($($arg:tt)*) => {
+        #[cfg(test)]
+        {
+            ::test_utils::record_event(format!($($arg)*));
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! use_parent_crate{
     ($($x:ident)::*) =>  {
         use $crate::$($x)::*;
     }
 }
+
+struct Entry<T, S> {
+    score: S,
+    doc_id: DocId,
+    item: T,
+}
+
+// Orders entries from best to worst: Higher score first, lower DocId on ties
+impl<T, S: Ord> Ord for Entry<T, S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.score.cmp(&self.score).then(self.doc_id.cmp(&other.doc_id))
+    }
+}
+
+impl<T, S: Ord> PartialOrd for Entry<T, S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, S: Ord> PartialEq for Entry<T, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, S: Ord> Eq for Entry<T, S> {}
+
+/// Keeps the best `k` items after skipping the best `offset` items.
+///
+/// Items are ranked by the score returned from `key`, highest first.
+/// Ties are broken by the returned `DocId`, lowest first.
+/// `push` costs O(log(offset + k)) and does not allocate for items that
+/// cannot make it into the result.
+pub struct TopKCollector<T, S, F> {
+    k: usize,
+    offset: usize,
+    key: F,
+    // Max-heap whose top is the worst entry kept
+    heap: BinaryHeap<Entry<T, S>>,
+}
+
+impl<T, S, F> TopKCollector<T, S, F>
+    where S: Ord,
+          F: Fn(&T) -> (S, DocId)
+{
+    pub fn new(k: usize, offset: usize, key: F) -> Self {
+        TopKCollector {
+            k: k,
+            offset: offset,
+            key: key,
+            heap: BinaryHeap::with_capacity(k + offset),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.k + self.offset
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.k == 0 {
+            return;
+        }
+        let (score, doc_id) = (self.key)(&item);
+        let entry = Entry {
+            score: score,
+            doc_id: doc_id,
+            item: item,
+        };
+        if self.heap.len() < self.capacity() {
+            self.heap.push(entry);
+        } else if let Some(mut worst) = self.heap.peek_mut() {
+            if entry < *worst {
+                *worst = entry;
+            }
+        }
+    }
+
+    /// The score an item has to beat to still make it into the result.
+    /// `None` as long as fewer than `offset + k` items were pushed.
+    pub fn current_kth_score(&self) -> Option<&S> {
+        if self.heap.len() == self.capacity() {
+            self.heap.peek().map(|entry| &entry.score)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the items ranked `offset..offset + k`, best first
+    pub fn into_sorted_vec(self) -> Vec<T> {
+        let offset = self.offset;
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .skip(offset)
+            .map(|entry| entry.item)
+            .collect()
+    }
+}
+
+/// Bloom filter used to answer "definitely not contained" quickly
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_hashes: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `expected_items` at the given false positive rate
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let n = expected_items.max(1) as f64;
+        let ln2 = 2f64.ln();
+        let num_bits = ((-n * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize).max(64);
+        let num_hashes = ((num_bits as f64 / n * ln2).round() as usize).max(1);
+        BloomFilter {
+            bits: vec![0; (num_bits + 63) / 64],
+            num_bits: num_bits,
+            num_hashes: num_hashes,
+            capacity: expected_items,
+        }
+    }
+
+    /// Number of items the filter was sized for
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn hashes<T: Hash + ?Sized>(item: &T) -> (u64, u64) {
+        let mut first = DefaultHasher::new();
+        item.hash(&mut first);
+        let mut second = DefaultHasher::new();
+        0x9e37_79b9_7f4a_7c15u64.hash(&mut second);
+        item.hash(&mut second);
+        (first.finish(), second.finish() | 1)
+    }
+
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let (h1, h2) = Self::hashes(item);
+        for i in 0..self.num_hashes as u64 {
+            let bit = (h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits as u64) as usize;
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if `item` was definitely never inserted
+    pub fn may_contain<T: Hash + ?Sized>(&self, item: &T) -> bool {
+        let (h1, h2) = Self::hashes(item);
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = (h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits as u64) as usize;
+            self.bits[bit / 64] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// Estimates the current false positive rate from the ratio of set bits
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        let set_bits = self.bits.iter().map(|b| b.count_ones() as usize).sum::<usize>();
+        (set_bits as f64 / self.num_bits as f64).powi(self.num_hashes as i32)
+    }
+}
+
+impl MemoryUsage for BloomFilter {
+    fn memory_report(&self) -> MemoryReport {
+        MemoryReport::leaf("bloom_filter", self.memory_usage())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use perlin_core::index::posting::DocId;
+    use super::{TopKCollector, BloomFilter};
+
+    fn items() -> Vec<(u32, u32)> {
+        // (score, doc_id) with plenty of ties
+        (0..1000).map(|i| ((i * 7919) % 37, i)).collect()
+    }
+
+    fn expected(items: &[(u32, u32)], k: usize, offset: usize) -> Vec<(u32, u32)> {
+        let mut sorted = items.to_vec();
+        sorted.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        sorted.into_iter().skip(offset).take(k).collect()
+    }
+
+    fn collect(items: &[(u32, u32)], k: usize, offset: usize) -> Vec<(u32, u32)> {
+        let mut collector = TopKCollector::new(k, offset, |&(score, doc_id): &(u32, u32)| {
+            (score, DocId(doc_id))
+        });
+        for item in items {
+            collector.push(*item);
+        }
+        collector.into_sorted_vec()
+    }
+
+    #[test]
+    fn same_as_sorting() {
+        let items = items();
+        for &(k, offset) in &[(10, 0), (10, 20), (1, 0), (0, 5), (1000, 0), (37, 963)] {
+            assert_eq!(collect(&items, k, offset), expected(&items, k, offset));
+        }
+    }
+
+    #[test]
+    fn offset_beyond_results() {
+        let items = items();
+        assert!(collect(&items, 10, 1000).is_empty());
+        assert_eq!(collect(&items, 10, 995).len(), 5);
+    }
+
+    #[test]
+    fn ties_by_doc_id() {
+        let items = vec![(1, 5), (1, 3), (1, 4), (1, 1), (1, 2)];
+        assert_eq!(collect(&items, 3, 1), vec![(1, 2), (1, 3), (1, 4)]);
+    }
+
+    #[test]
+    fn threshold() {
+        let mut collector = TopKCollector::new(5, 0, |&(score, doc_id): &(u32, u32)| {
+            (score, DocId(doc_id))
+        });
+        let mut last = None;
+        for item in items() {
+            collector.push(item);
+            let current = collector.current_kth_score().cloned();
+            if last.is_some() {
+                assert!(current >= last);
+            }
+            last = current;
+        }
+        assert_eq!(last, Some(36));
+    }
+
+    #[test]
+    fn bloom_filter() {
+        let mut bloom = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000 {
+            bloom.insert(&format!("term{}", i));
+        }
+        // No false negatives
+        assert!((0..10_000).all(|i| bloom.may_contain(&format!("term{}", i))));
+        let false_positives = (0..10_000)
+            .filter(|i| bloom.may_contain(&format!("other{}", i)))
+            .count();
+        assert!(false_positives < 200, format!("{} false positives", false_positives));
+        assert!(bloom.estimated_false_positive_rate() < 0.02);
+    }
+}
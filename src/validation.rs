@@ -0,0 +1,164 @@
+//! Index-time document validation.
+//!
+//! A `Validator` is consulted by the derived index's `add_document` before any
+//! pipeline runs. Rejected documents get no `DocId` and leave the index
+//! untouched. Warnings are returned to the caller and, if set, passed to the
+//! index's `WarningSink` together with the assigned `DocId` and external id.
+use std::borrow::Cow;
+
+use perlin_core::index::posting::DocId;
+
+/// The document as it is passed to `add_document`: (field name, content)
+pub type Document<'a> = [(Cow<'a, str>, Cow<'a, str>)];
+
+pub type Validator = Box<for<'a> Fn(&Document<'a>) -> ValidationOutcome + Sync + Send>;
+
+/// Receives the warnings of every document accepted with warnings,
+/// after it was indexed
+///
+/// `T` is the external id type of the index. Indexes without external ids
+/// pass `None`.
+pub type WarningSink<T = ()> = Box<Fn(DocId, Option<&T>, &[String]) + Sync + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationOutcome {
+    Accept,
+    AcceptWithWarnings(Vec<String>),
+    Reject { reason: String },
+}
+
+impl ValidationOutcome {
+    pub fn is_reject(&self) -> bool {
+        match *self {
+            ValidationOutcome::Reject { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Combines two outcomes. The first rejection wins, warnings are collected.
+    pub fn and(self, other: ValidationOutcome) -> ValidationOutcome {
+        use self::ValidationOutcome::*;
+        match (self, other) {
+            (reject @ Reject { .. }, _) |
+            (_, reject @ Reject { .. }) => reject,
+            (Accept, other) | (other, Accept) => other,
+            (AcceptWithWarnings(mut a), AcceptWithWarnings(mut b)) => {
+                a.append(&mut b);
+                AcceptWithWarnings(a)
+            }
+        }
+    }
+}
+
+fn field_contents<'b, 'a: 'b>(document: &'b Document<'a>,
+                              field: &'b str)
+                              -> Box<Iterator<Item = &'b str> + 'b> {
+    Box::new(document.iter()
+        .filter(move |&&(ref name, _)| name == field)
+        .map(|&(_, ref content)| content.as_ref()))
+}
+
+/// Rejects documents in which `field` is missing or consists only of whitespace
+pub fn required_field(field: &str) -> Validator {
+    let field = field.to_string();
+    Box::new(move |document| {
+        if field_contents(document, &field).any(|content| !content.trim().is_empty()) {
+            ValidationOutcome::Accept
+        } else {
+            ValidationOutcome::Reject { reason: format!("Field {} is empty or missing", field) }
+        }
+    })
+}
+
+/// Rejects documents in which `field` is longer than `max` bytes
+pub fn max_field_length(field: &str, max: usize) -> Validator {
+    let field = field.to_string();
+    Box::new(move |document| {
+        if let Some(len) = field_contents(document, &field).map(str::len).find(|len| *len > max) {
+            ValidationOutcome::Reject {
+                reason: format!("Field {} is {} bytes long. Maximum is {}", field, len, max),
+            }
+        } else {
+            ValidationOutcome::Accept
+        }
+    })
+}
+
+/// Rejects documents in which `field` has more than `max` tokens
+/// The number of tokens is estimated by splitting on whitespace
+pub fn max_token_count(field: &str, max: usize) -> Validator {
+    let field = field.to_string();
+    Box::new(move |document| {
+        if let Some(count) = field_contents(document, &field)
+            .map(|content| content.split_whitespace().count())
+            .find(|count| *count > max) {
+            ValidationOutcome::Reject {
+                reason: format!("Field {} has {} tokens. Maximum is {}", field, count, max),
+            }
+        } else {
+            ValidationOutcome::Accept
+        }
+    })
+}
+
+/// Turns rejections of `validator` into warnings
+pub fn warn_only(validator: Validator) -> Validator {
+    Box::new(move |document| match validator(document) {
+        ValidationOutcome::Reject { reason } => ValidationOutcome::AcceptWithWarnings(vec![reason]),
+        outcome => outcome,
+    })
+}
+
+/// Runs all validators and combines their outcomes
+pub fn all(validators: Vec<Validator>) -> Validator {
+    Box::new(move |document| {
+        validators.iter()
+            .fold(ValidationOutcome::Accept,
+                  |outcome, validator| outcome.and(validator(document)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use super::*;
+
+    fn doc<'a>(fields: &[(&'a str, &'a str)]) -> Vec<(Cow<'a, str>, Cow<'a, str>)> {
+        fields.iter().map(|&(f, c)| (Cow::from(f), Cow::from(c))).collect()
+    }
+
+    #[test]
+    fn required() {
+        let validator = required_field("title");
+        assert_eq!(validator(&doc(&[("title", "Deimos")])), ValidationOutcome::Accept);
+        assert!(validator(&doc(&[("title", "  ")])).is_reject());
+        assert!(validator(&doc(&[("text", "Deimos")])).is_reject());
+    }
+
+    #[test]
+    fn max_length() {
+        let validator = max_field_length("title", 6);
+        assert_eq!(validator(&doc(&[("title", "Deimos")])), ValidationOutcome::Accept);
+        assert!(validator(&doc(&[("title", "Phobos!")])).is_reject());
+        assert_eq!(validator(&doc(&[("text", "Phobos!")])), ValidationOutcome::Accept);
+    }
+
+    #[test]
+    fn max_tokens() {
+        let validator = max_token_count("text", 2);
+        assert_eq!(validator(&doc(&[("text", " birds  flew ")])), ValidationOutcome::Accept);
+        assert!(validator(&doc(&[("text", "birds flew away")])).is_reject());
+    }
+
+    #[test]
+    fn combined() {
+        let validator = all(vec![required_field("title"),
+                                 warn_only(max_token_count("text", 1)),
+                                 warn_only(max_field_length("text", 3))]);
+        assert_eq!(validator(&doc(&[("title", "Deimos"), ("text", "birds flew")])),
+                   ValidationOutcome::AcceptWithWarnings(vec![
+                       "Field text has 2 tokens. Maximum is 1".to_string(),
+                       "Field text is 10 bytes long. Maximum is 3".to_string()]));
+        assert!(validator(&doc(&[("text", "birds flew")])).is_reject());
+    }
+}